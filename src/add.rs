@@ -0,0 +1,199 @@
+use crate::edit::{restore_stowfile, write_stowfile, Document};
+use crate::init::source_name;
+use crate::settings::Settings;
+use crate::vars::{escape_variables, path_text, split_path_variable};
+use anyhow::{bail, Context, Result};
+use log::{error, info};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Where a new source goes in the stow dir when '--as' isn't given. The file keeps its path
+/// relative to the directory its target variable points at, with hidden names renamed to 'dot-'
+fn default_source_path(file: &Path) -> PathBuf {
+    let relative = match split_path_variable(file) {
+        Some((_, rest)) => rest,
+        None => file.file_name().map(PathBuf::from).unwrap_or_default(),
+    };
+    relative
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(source_name(&name.to_string_lossy())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Move a file into the stow dir, add it to the stowfile, and link it back into place.
+/// If any step fails, the steps before it are undone
+pub fn add_file(settings: &Settings, file: &Path, source: Option<PathBuf>) -> Result<()> {
+    let file = std::path::absolute(file)?;
+    let metadata =
+        fs::symlink_metadata(&file).with_context(|| format!("Unable to add {:?}", file))?;
+    if metadata.file_type().is_symlink() {
+        bail!("{:?} is already a symlink", file);
+    }
+    let stow_dir = settings.current_working_dir().canonicalize()?;
+    if file.starts_with(&stow_dir) {
+        bail!("{:?} is already in the stow dir {:?}", file, stow_dir);
+    }
+
+    let source = source.unwrap_or_else(|| default_source_path(&file));
+    // '..' could leave the stow dir
+    let escapes = source
+        .components()
+        .any(|component| component == Component::ParentDir);
+    if source.is_absolute() || source.as_os_str().is_empty() || escapes {
+        bail!(
+            "'--as' must be a path inside the stow dir, not {:?}",
+            source
+        );
+    }
+    let dest = stow_dir.join(&source);
+    if dest.exists() {
+        bail!("{:?} already exists in the stow dir", dest);
+    }
+    let src = escape_variables(&source.display().to_string());
+    let target = path_text(&file);
+
+    let stowfile_path = settings.stowfile_path();
+    let original = fs::read_to_string(stowfile_path)?;
+    let mut stowfile = Document::parse(&original)?;
+    stowfile.add_entry(&src, std::slice::from_ref(&target))?;
+    let updated = stowfile.into_text()?;
+
+    if settings.link_settings().dry_run() {
+        info!("Pretending to move {:?} to {:?}", file, dest);
+        info!(
+            "Pretending to add {:?} -> {:?} to {:?}",
+            src, target, stowfile_path
+        );
+        info!("Pretending to link {:?} -> {:?}", dest, file);
+        return Ok(());
+    }
+
+    // Parent dirs that have to be created for the file, outermost first
+    let mut created_dirs: Vec<PathBuf> = dest
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| !dir.exists())
+        .map(Path::to_path_buf)
+        .collect();
+    created_dirs.reverse();
+    let remove_created_dirs = || {
+        for dir in created_dirs.iter().rev() {
+            if let Err(e) = fs::remove_dir(dir) {
+                error!("Unable to remove {:?}: {}", dir, e);
+            }
+        }
+    };
+
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    info!("Moving {:?} to {:?}", file, dest);
+    if let Err(e) = fs::rename(&file, &dest) {
+        remove_created_dirs();
+        return Err(e).with_context(|| format!("Unable to move {:?} to {:?}", file, dest));
+    }
+    let move_back = || {
+        info!("Moving {:?} back to {:?}", dest, file);
+        if let Err(e) = fs::rename(&dest, &file) {
+            error!("Unable to move {:?} back to {:?}: {}", dest, file, e);
+        }
+        remove_created_dirs();
+    };
+
+    info!("Adding {:?} -> {:?} to {:?}", src, target, stowfile_path);
+    if let Err(e) = write_stowfile(settings, &original, &updated) {
+        move_back();
+        return Err(e);
+    }
+
+    info!("Linking {:?} -> {:?}", dest, file);
+    if let Err(e) = std::os::unix::fs::symlink(&dest, &file) {
+        restore_stowfile(settings, &original);
+        move_back();
+        return Err(e).with_context(|| format!("Unable to link {:?} -> {:?}", dest, file));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::StowfileSettings;
+    use crate::testing::{scratch_dir, settings};
+
+    /// A stow dir whose stowfile links 'other' to '.bashrc', and a home dir with a '.bashrc' to add
+    fn stow_dir_and_home(name: &str) -> (Settings, PathBuf, PathBuf) {
+        let root = scratch_dir(name);
+        let stow_dir = root.join("dotfiles");
+        let home = root.join("home");
+        fs::create_dir_all(&stow_dir).unwrap();
+        fs::create_dir_all(&home).unwrap();
+        fs::write(stow_dir.join("other"), "").unwrap();
+        fs::write(home.join(".bashrc"), "export EDITOR=vi\n").unwrap();
+        let stowfile = stow_dir.join("stowfile");
+        fs::write(
+            &stowfile,
+            format!(
+                "version: 1\n\nstow:\n  - src: other\n    links:\n      - {}/.bashrc\n",
+                home.display()
+            ),
+        )
+        .unwrap();
+        let settings = settings(
+            vec![StowfileSettings::new(stowfile, stow_dir.clone())],
+            stow_dir.clone(),
+            false,
+        );
+        (settings, stow_dir, home)
+    }
+
+    #[test]
+    fn added_file_is_moved_and_linked_back() {
+        let (settings, stow_dir, home) = stow_dir_and_home("add");
+        fs::write(home.join(".vimrc"), "set number\n").unwrap();
+        add_file(
+            &settings,
+            &home.join(".vimrc"),
+            Some(PathBuf::from("vim/vimrc")),
+        )
+        .unwrap();
+
+        let dest = stow_dir.join("vim/vimrc");
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "set number\n");
+        assert_eq!(fs::read_link(home.join(".vimrc")).unwrap(), dest);
+        let stowfile = fs::read_to_string(settings.stowfile_path()).unwrap();
+        assert!(stowfile.contains("- src: vim/vimrc"));
+    }
+
+    #[test]
+    fn failing_stowfile_write_puts_the_file_back() {
+        let (settings, stow_dir, home) = stow_dir_and_home("add-rollback");
+        let original = fs::read_to_string(settings.stowfile_path()).unwrap();
+
+        // The new entry's target clashes with 'other', so the edited stowfile doesn't load
+        let result = add_file(
+            &settings,
+            &home.join(".bashrc"),
+            Some(PathBuf::from("bash/bashrc")),
+        );
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Unable to change the stowfile"
+        );
+
+        let metadata = fs::symlink_metadata(home.join(".bashrc")).unwrap();
+        assert!(metadata.file_type().is_file());
+        assert_eq!(
+            fs::read_to_string(home.join(".bashrc")).unwrap(),
+            "export EDITOR=vi\n"
+        );
+        assert!(!stow_dir.join("bash").exists());
+        assert_eq!(
+            fs::read_to_string(settings.stowfile_path()).unwrap(),
+            original
+        );
+    }
+}
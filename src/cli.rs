@@ -0,0 +1,602 @@
+use crate::plan::{Change, Operation};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgGroup, ArgMatches, CommandFactory, Parser, Subcommand};
+use clap_verbosity_flag::{InfoLevel, Verbosity};
+use std::path::PathBuf;
+
+// Ids of the GNU Stow style action flags, along with the change each one makes
+const CHANGE_FLAGS: &[(&str, Change)] = &[
+    ("stow", Change::Stow),
+    ("unstow", Change::Unstow),
+    ("delete", Change::Unstow),
+    ("restow", Change::Restow),
+];
+
+/// New Stow - manage famrs of symbolic links with stowfiles
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+#[command(group(
+            ArgGroup::new("test")
+                .required(false)
+                .args(["dry_run", "simulate", "no"]),
+        ))]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[clap(flatten)]
+    pub verbose: Verbosity<InfoLevel>,
+
+    /// Sets a custom stowfilefile
+    #[arg(long, value_name = "FILE", global = true)]
+    pub stowfile: Option<PathBuf>,
+
+    /// Sets the working directory to "DIR" instead of the current directory.
+    /// Commands will be performed as if nstow was invoked from this directory.
+    /// When combined with '--stowfile' paths in the stowfile will be intrepreted relative to
+    /// "DIR", rather than the current working directory.
+    /// Defaults to $NSTOW_DIR or 'dir' in the config file.
+    #[arg(short, long, value_name = "DIR", global = true)]
+    pub dir: Option<PathBuf>,
+
+    /// Do not perform any actions, just show what would be done.
+    /// This option is useless when combined with '-qqq'.
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Alias for '--dry-run' to preserve some compatibility with GNU Stow.
+    #[arg(short, long, global = true)]
+    pub no: bool,
+
+    /// Alias for '--dry-run' to preserve some compatibility with GNU Stow.
+    #[arg(long, global = true)]
+    pub simulate: bool,
+
+    /// Set a variable, overriding any definition from the stowfile or a vars file.
+    /// This flag may be passed multiple times.
+    #[arg(long, value_name = "KEY=VALUE", action = ArgAction::Append, global = true)]
+    pub var: Option<Vec<String>>,
+
+    /// Load variables from a YAML ('.yaml' or '.yml') or .env file.
+    /// These override the stowfile's variables, but are overridden by '--var'.
+    /// This flag may be passed multiple times, with later files taking precedence.
+    #[arg(long, value_name = "FILE", action = ArgAction::Append, global = true)]
+    pub vars_file: Option<Vec<PathBuf>>,
+
+    /// Allow variables to run commands with '${cmd:COMMAND}'.
+    #[arg(long, global = true)]
+    pub allow_commands: bool,
+
+    /// Allow the stowfile's hooks to run. Without this, hooks are skipped.
+    #[arg(long, global = true)]
+    pub allow_hooks: bool,
+
+    /// Age identity file for decrypting encrypted sources, overriding 'age_identity' in the stowfile.
+    #[arg(long, value_name = "FILE", global = true)]
+    pub identity: Option<PathBuf>,
+
+    /// Use every stowfile under the working directory together, instead of a single stowfile.
+    /// Sources in each stowfile are relative to that stowfile's directory.
+    #[arg(long, global = true, conflicts_with = "stowfile")]
+    pub recursive: bool,
+
+    /// Use a stow dir or stowfile as a layer. This flag may be passed multiple times, with later
+    /// layers taking precedence: a later layer's link replaces an earlier layer's link to the same
+    /// target. Sources in each layer are relative to that layer's directory.
+    #[arg(long, value_name = "PATH", action = ArgAction::Append, global = true, conflicts_with_all = ["stowfile", "recursive"])]
+    pub layer: Option<Vec<PathBuf>>,
+
+    /// Unstow the packages that follow. Like GNU Stow, '-D', '-S', and '-R' may be mixed in one
+    /// run, e.g. 'nstow -D old -S new'.
+    #[arg(long, action = ArgAction::Count)]
+    pub unstow: u8,
+
+    /// Alias for '--unstow' to preserve some compatibility with GNU Stow.
+    #[arg(short = 'D', long, action = ArgAction::Count)]
+    pub delete: u8,
+
+    /// Stow the packages that follow. Packages before any of '-D', '-S', or '-R' are stowed.
+    #[arg(short = 'S', long, action = ArgAction::Count)]
+    pub stow: u8,
+
+    /// Restow the packages that follow.
+    #[arg(short = 'R', long, action = ArgAction::Count)]
+    pub restow: u8,
+
+    /// Alias for the 'status' command.
+    #[arg(long, short, conflicts_with_all = ["stow", "unstow", "delete", "restow"])]
+    pub status: bool,
+
+    #[clap(flatten)]
+    pub selection: SelectionArgs,
+
+    #[clap(flatten)]
+    pub link_args: LinkArgs,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Create symlinks specified by the stowfile.
+    Stow {
+        #[clap(flatten)]
+        selection: SelectionArgs,
+
+        #[clap(flatten)]
+        link_args: LinkArgs,
+    },
+
+    /// Remove previously created symlinks.
+    #[command(visible_alias = "delete")]
+    Unstow {
+        #[clap(flatten)]
+        selection: SelectionArgs,
+    },
+
+    /// Restow by deleting any existing links before linking again.
+    /// Shortcut for 'nstow unstow && nstow stow'
+    Restow {
+        #[clap(flatten)]
+        selection: SelectionArgs,
+
+        #[clap(flatten)]
+        link_args: LinkArgs,
+    },
+
+    /// Show the status of links in the stowfile.
+    Status {
+        #[clap(flatten)]
+        selection: SelectionArgs,
+    },
+
+    /// Check the stowfile for errors without changing anything.
+    /// Every entry is checked, including entries in packages and entries with tags.
+    Validate,
+
+    /// Write a stowfile that links every file in the working directory.
+    /// Files and directories named 'dot-NAME' are linked to '.NAME'.
+    Init {
+        /// Directory to link files into.
+        /// Defaults to $NSTOW_TARGET, 'target' in the config file, or '${HOME}'.
+        #[arg(long, value_name = "DIR")]
+        target: Option<String>,
+
+        /// Overwrite an existing stowfile
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Move a file into the working directory, add it to the stowfile, and link it back into place.
+    Add {
+        /// The file to put under management
+        file: PathBuf,
+
+        /// Where to put the file, relative to the working directory.
+        /// Defaults to the file's path relative to its XDG or home directory.
+        #[arg(long = "as", value_name = "PATH")]
+        source: Option<PathBuf>,
+    },
+
+    /// Move a source file within the working directory, updating its stowfile entry and any links
+    /// to it.
+    Mv {
+        /// The source to move, relative to the working directory
+        from: PathBuf,
+
+        /// Where to move it, relative to the working directory
+        to: PathBuf,
+    },
+
+    /// Print stowfile entries for existing symlinks that point into the working directory.
+    Import {
+        /// Directories to search for symlinks, such as your home directory
+        #[arg(value_name = "ROOT", required = true)]
+        roots: Vec<PathBuf>,
+
+        /// Add the entries to the stowfile instead of printing them
+        #[arg(long)]
+        write: bool,
+    },
+
+    /// Rewrite the stowfile with consistent indentation and quoting, keeping comments.
+    Fmt {
+        /// Don't change the stowfile, but fail if it isn't formatted
+        #[arg(long)]
+        check: bool,
+
+        /// Sort the entries in each 'stow' list by source
+        #[arg(long)]
+        sort: bool,
+    },
+
+    /// Upgrade the stowfile to the current stowfile format version, keeping comments.
+    Migrate,
+
+    /// Print the JSON Schema for stowfiles, for editors with a YAML language server.
+    Schema,
+
+    /// Show the settings from the config file, the environment, and the command line, and where
+    /// each one came from.
+    Config,
+
+    /// Change the variables in the stowfile's 'vars' section.
+    Var {
+        #[command(subcommand)]
+        command: VarCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum VarCommand {
+    /// Set a variable, replacing its value if the stowfile already defines it.
+    Set {
+        #[arg(value_name = "KEY=VALUE")]
+        definition: String,
+    },
+
+    /// Remove a variable.
+    Unset { key: String },
+}
+
+/// Options that select which of the stowfile's entries an action applies to
+#[derive(clap::Args, Debug, Default)]
+pub struct SelectionArgs {
+    /// Only act on these packages from the stowfile, along with the packages they require.
+    /// By default, every entry in the stowfile is used.
+    #[arg(value_name = "PACKAGE")]
+    pub packages: Vec<String>,
+
+    /// Ignore source files that match this regex.
+    /// This flag may be passed multiple times and combined with '--only'.
+    #[arg(long, value_name = "REGEX", action = ArgAction::Append)]
+    pub ignore: Option<Vec<String>>,
+
+    /// Only stow source files that match this regex.
+    /// This flag may be passed multiple times and combined with '--ignore'.
+    #[arg(long, value_name = "REGEX", action = ArgAction::Append)]
+    pub only: Option<Vec<String>>,
+
+    /// Ignore targets that match this regex.
+    /// This flag may be passed multiple times and combined with '--only-target'.
+    #[arg(long, value_name = "REGEX", action = ArgAction::Append)]
+    pub ignore_target: Option<Vec<String>>,
+
+    /// Only stow targets that match this regex.
+    /// This flag may be passed multiple times and combined with '--ignore-target'.
+    #[arg(long, value_name = "REGEX", action = ArgAction::Append)]
+    pub only_target: Option<Vec<String>>,
+
+    /// Only stow entries with this tag.
+    /// This flag may be passed multiple times, in which case entries with any of the tags are stowed.
+    #[arg(long, value_name = "TAG", action = ArgAction::Append)]
+    pub tag: Option<Vec<String>>,
+
+    /// Do not stow entries with this tag, even if they match '--tag'.
+    /// This flag may be passed multiple times.
+    #[arg(long, value_name = "TAG", action = ArgAction::Append)]
+    pub skip_tag: Option<Vec<String>>,
+}
+
+/// Options for actions that create links
+#[derive(clap::Args, Debug, Default)]
+pub struct LinkArgs {
+    /// Sets a directory for backuping up any existing files at target locations.
+    /// This option may be used with '--override', in which case all files are backed up except
+    /// those marked for overriding.
+    #[arg(short, long, value_name = "BACKUP_DIR")]
+    pub backup: Option<PathBuf>,
+
+    /// Force overriding of any existing targets or files at target locations.
+    #[arg(long, value_name = "REGEX", action = ArgAction::Append)]
+    pub r#override: Option<Vec<String>>,
+    // TODO: add a '--restore' flag to restore the last backups when unstowing
+    // Save the last backups in subdir and restore files from that subdir
+}
+
+/// Everything nstow can do
+#[derive(Debug)]
+pub enum Action {
+    Apply(Vec<Operation>),
+    Status(Vec<String>),
+    Validate,
+    Init {
+        target: Option<String>,
+        force: bool,
+    },
+    Import {
+        roots: Vec<PathBuf>,
+        write: bool,
+    },
+    Add {
+        file: PathBuf,
+        source: Option<PathBuf>,
+    },
+    Move {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    Format {
+        check: bool,
+        sort: bool,
+    },
+    Migrate,
+    Schema,
+    Config,
+    SetVar {
+        definition: String,
+    },
+    UnsetVar {
+        key: String,
+    },
+}
+
+/// Split GNU Stow style flags and packages into operations. Each package belongs to the action
+/// flag before it, and a flag with no packages after it applies to every entry in the stowfile.
+/// That is only allowed for a lone flag, see 'Args::check_change_flags'
+fn operations_from_flags(matches: &ArgMatches) -> Vec<Operation> {
+    let mut flags: Vec<(usize, Change)> = Vec::new();
+    for (id, change) in CHANGE_FLAGS {
+        // Unused flags still have an index for their default value
+        if matches.value_source(id) != Some(ValueSource::CommandLine) {
+            continue;
+        }
+        if let Some(indices) = matches.indices_of(id) {
+            flags.extend(indices.map(|index| (index, *change)));
+        }
+    }
+    flags.sort_by_key(|(index, _)| *index);
+
+    let mut packages: Vec<(usize, String)> = Vec::new();
+    if let (Some(indices), Some(names)) = (
+        matches.indices_of("packages"),
+        matches.get_many::<String>("packages"),
+    ) {
+        packages.extend(indices.zip(names.cloned()));
+    }
+
+    let mut operations = Vec::new();
+    let leading: Vec<String> = packages
+        .iter()
+        .filter(|(index, _)| flags.first().is_none_or(|(first, _)| index < first))
+        .map(|(_, name)| name.clone())
+        .collect();
+    if !leading.is_empty() || flags.is_empty() {
+        operations.push(Operation::new(Change::Stow, leading));
+    }
+    for (position, (index, change)) in flags.iter().enumerate() {
+        let next = flags.get(position + 1).map(|(next, _)| *next);
+        let names = packages
+            .iter()
+            .filter(|(package, _)| package > index && next.is_none_or(|next| *package < next))
+            .map(|(_, name)| name.clone())
+            .collect();
+        operations.push(Operation::new(*change, names));
+    }
+    operations
+}
+
+impl Args {
+    /// Global options may come before a subcommand, but the GNU Stow style flags and packages
+    /// can't be combined with one
+    pub fn check_subcommand_conflicts(matches: &ArgMatches) -> Result<(), clap::Error> {
+        let Some((name, _)) = matches.subcommand() else {
+            return Ok(());
+        };
+        let mut command = Args::command();
+        let conflict = command
+            .get_arguments()
+            .filter(|arg| !arg.is_global_set())
+            .filter(|arg| !["verbose", "quiet"].contains(&arg.get_id().as_str()))
+            .find(|arg| {
+                matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+            })
+            .map(|arg| match arg.get_long() {
+                Some(long) => format!("--{}", long),
+                None => arg.get_id().to_string().to_uppercase(),
+            });
+        match conflict {
+            Some(arg) => Err(command.error(
+                ErrorKind::ArgumentConflict,
+                format!("'{}' can't be used with the '{}' command", arg, name),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// A flag with no packages means every entry in the stowfile, which is surprising after other
+    /// flags: 'nstow -D bash -S' would unstow bash and then stow everything. So when flags are
+    /// mixed, each one needs packages
+    pub fn check_change_flags(matches: &ArgMatches) -> Result<(), clap::Error> {
+        if matches.subcommand().is_some() {
+            return Ok(());
+        }
+        let operations = operations_from_flags(matches);
+        if operations.len() > 1 && operations.iter().any(|op| op.packages().is_empty()) {
+            return Err(Args::command().error(
+                ErrorKind::MissingRequiredArgument,
+                "When '-S', '-D', and '-R' are mixed, each one needs packages after it. A flag on its own, with no packages, applies to every entry in the stowfile",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Split the parsed args into the requested action and the options for that action.
+    /// The GNU Stow style flags are aliases for the matching subcommand
+    pub fn into_action(self, matches: &ArgMatches) -> (Action, SelectionArgs, LinkArgs) {
+        match self.command {
+            Some(Command::Stow {
+                mut selection,
+                link_args,
+            }) => {
+                let packages = std::mem::take(&mut selection.packages);
+                let operation = Operation::new(Change::Stow, packages);
+                (Action::Apply(vec![operation]), selection, link_args)
+            }
+            Some(Command::Unstow { mut selection }) => {
+                let packages = std::mem::take(&mut selection.packages);
+                let operation = Operation::new(Change::Unstow, packages);
+                (
+                    Action::Apply(vec![operation]),
+                    selection,
+                    LinkArgs::default(),
+                )
+            }
+            Some(Command::Restow {
+                mut selection,
+                link_args,
+            }) => {
+                let packages = std::mem::take(&mut selection.packages);
+                let operation = Operation::new(Change::Restow, packages);
+                (Action::Apply(vec![operation]), selection, link_args)
+            }
+            Some(Command::Status { mut selection }) => {
+                let packages = std::mem::take(&mut selection.packages);
+                (Action::Status(packages), selection, LinkArgs::default())
+            }
+            Some(Command::Validate) => (
+                Action::Validate,
+                SelectionArgs::default(),
+                LinkArgs::default(),
+            ),
+            Some(Command::Init { target, force }) => (
+                Action::Init { target, force },
+                SelectionArgs::default(),
+                LinkArgs::default(),
+            ),
+            Some(Command::Add { file, source }) => (
+                Action::Add { file, source },
+                SelectionArgs::default(),
+                LinkArgs::default(),
+            ),
+            Some(Command::Mv { from, to }) => (
+                Action::Move { from, to },
+                SelectionArgs::default(),
+                LinkArgs::default(),
+            ),
+            Some(Command::Import { roots, write }) => (
+                Action::Import { roots, write },
+                SelectionArgs::default(),
+                LinkArgs::default(),
+            ),
+            Some(Command::Fmt { check, sort }) => (
+                Action::Format { check, sort },
+                SelectionArgs::default(),
+                LinkArgs::default(),
+            ),
+            Some(Command::Migrate) => (
+                Action::Migrate,
+                SelectionArgs::default(),
+                LinkArgs::default(),
+            ),
+            Some(Command::Schema) => (
+                Action::Schema,
+                SelectionArgs::default(),
+                LinkArgs::default(),
+            ),
+            Some(Command::Config) => (
+                Action::Config,
+                SelectionArgs::default(),
+                LinkArgs::default(),
+            ),
+            Some(Command::Var { command }) => {
+                let action = match command {
+                    VarCommand::Set { definition } => Action::SetVar { definition },
+                    VarCommand::Unset { key } => Action::UnsetVar { key },
+                };
+                (action, SelectionArgs::default(), LinkArgs::default())
+            }
+            None => {
+                let mut selection = self.selection;
+                let packages = std::mem::take(&mut selection.packages);
+                let action = if self.status {
+                    Action::Status(packages)
+                } else {
+                    Action::Apply(operations_from_flags(matches))
+                };
+                (action, selection, self.link_args)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(args: &[&str]) -> ArgMatches {
+        Args::command()
+            .try_get_matches_from(std::iter::once("nstow").chain(args.iter().copied()))
+            .unwrap()
+    }
+
+    fn operations(args: &[&str]) -> Vec<(Change, Vec<String>)> {
+        operations_from_flags(&matches(args))
+            .into_iter()
+            .map(|op| (op.change(), op.packages().to_vec()))
+            .collect()
+    }
+
+    fn packages(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn packages_without_flags_are_stowed() {
+        assert_eq!(operations(&[]), [(Change::Stow, packages(&[]))]);
+        assert_eq!(
+            operations(&["bash", "vim"]),
+            [(Change::Stow, packages(&["bash", "vim"]))]
+        );
+    }
+
+    #[test]
+    fn each_flag_takes_the_packages_after_it() {
+        assert_eq!(
+            operations(&["-D", "old", "-S", "new", "newer", "-R", "bash"]),
+            [
+                (Change::Unstow, packages(&["old"])),
+                (Change::Stow, packages(&["new", "newer"])),
+                (Change::Restow, packages(&["bash"])),
+            ]
+        );
+        assert_eq!(
+            operations(&["vim", "--delete", "old", "--stow", "new"]),
+            [
+                (Change::Stow, packages(&["vim"])),
+                (Change::Unstow, packages(&["old"])),
+                (Change::Stow, packages(&["new"])),
+            ]
+        );
+        assert_eq!(
+            operations(&["-S", "a", "-D", "b", "-S", "c"]),
+            [
+                (Change::Stow, packages(&["a"])),
+                (Change::Unstow, packages(&["b"])),
+                (Change::Stow, packages(&["c"])),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_lone_flag_applies_to_everything() {
+        assert_eq!(operations(&["-D"]), [(Change::Unstow, packages(&[]))]);
+        assert!(Args::check_change_flags(&matches(&["-D"])).is_ok());
+        assert!(Args::check_change_flags(&matches(&["-R", "bash"])).is_ok());
+    }
+
+    #[test]
+    fn mixed_flags_each_need_packages() {
+        for args in [
+            &["-D", "bash", "-S"][..],
+            &["-S", "-D", "bash"],
+            &["bash", "-D"],
+            &["-D", "-S"],
+        ] {
+            assert!(
+                Args::check_change_flags(&matches(args)).is_err(),
+                "{:?}",
+                args
+            );
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::vars::xdg_home_dir;
+use anyhow::{Context, Result};
+use directories_next::BaseDirs;
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const DIR_VAR: &str = "NSTOW_DIR";
+const TARGET_VAR: &str = "NSTOW_TARGET";
+
+/// Where 'nstow init' links files to when nothing else says
+const DEFAULT_TARGET: &str = "${HOME}";
+
+/// The user's config file. Every key is optional
+// TODO: add keys for the conflict policy, link style, and output format once nstow has options
+// for them. Keys are only accepted for options that exist, so none are ignored
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    dir: Option<PathBuf>,
+    target: Option<String>,
+}
+
+/// Where a setting's value came from
+#[derive(Debug, Clone)]
+pub enum Source {
+    Default,
+    ConfigFile,
+    Environment(&'static str),
+    CommandLine,
+}
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::ConfigFile => write!(f, "config file"),
+            Source::Environment(key) => write!(f, "${}", key),
+            Source::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// A setting's value, along with where it came from
+#[derive(Debug)]
+pub struct Setting<T> {
+    value: T,
+    source: Source,
+}
+impl<T> Setting<T> {
+    fn new(value: T, source: Source) -> Self {
+        Setting { value, source }
+    }
+
+    /// Replace the value with one from a source that takes precedence, if it was given
+    fn or(self, value: Option<T>, source: Source) -> Self {
+        match value {
+            Some(value) => Setting::new(value, source),
+            None => self,
+        }
+    }
+
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    pub fn source(&self) -> &Source {
+        &self.source
+    }
+}
+
+/// The config file's path, '$XDG_CONFIG_HOME/nstow/config.yaml'
+fn config_path() -> Option<PathBuf> {
+    Some(
+        xdg_home_dir("XDG_CONFIG_HOME")?
+            .join("nstow")
+            .join("config.yaml"),
+    )
+}
+
+/// An environment variable, treating an empty value as unset
+fn env_var<T: From<String>>(key: &str) -> Option<T> {
+    env::var(key)
+        .ok()
+        .filter(|value| !value.is_empty())
+        .map(T::from)
+}
+
+/// Expand a leading '~', since paths from the config file and the environment aren't expanded by
+/// a shell
+fn expand_home(path: PathBuf) -> PathBuf {
+    let Ok(rest) = path.strip_prefix("~") else {
+        return path;
+    };
+    match BaseDirs::new() {
+        Some(base_dirs) => base_dirs.home_dir().join(rest),
+        None => path,
+    }
+}
+
+/// Defaults for command line options, from the config file and 'NSTOW_*' environment variables.
+/// The command line takes precedence over the environment, which takes precedence over the config
+/// file
+#[derive(Debug)]
+pub struct Config {
+    path: Option<PathBuf>,
+    found: bool,
+    dir: Setting<PathBuf>,
+    target: Setting<String>,
+}
+impl Config {
+    pub fn load(current_dir: &Path, dir: Option<PathBuf>, target: Option<String>) -> Result<Self> {
+        let path = config_path();
+        let mut found = false;
+        let mut file = ConfigFile::default();
+        if let Some(path) = path.as_ref().filter(|path| path.exists()) {
+            let contents = fs::read_to_string(path)
+                .with_context(|| format!("Unable to read config file {:?}", path))?;
+            // An empty config file, or one with only comments, is null
+            let parsed: Option<ConfigFile> = serde_yaml::from_str(&contents)
+                .with_context(|| format!("Malformatted config file {:?}", path))?;
+            file = parsed.unwrap_or_default();
+            found = true;
+        }
+
+        let dir = Setting::new(current_dir.to_path_buf(), Source::Default)
+            .or(file.dir.map(expand_home), Source::ConfigFile)
+            .or(
+                env_var(DIR_VAR).map(expand_home),
+                Source::Environment(DIR_VAR),
+            )
+            .or(dir, Source::CommandLine);
+        let target = Setting::new(DEFAULT_TARGET.to_string(), Source::Default)
+            .or(file.target, Source::ConfigFile)
+            .or(env_var(TARGET_VAR), Source::Environment(TARGET_VAR))
+            .or(target, Source::CommandLine);
+
+        Ok(Config {
+            path,
+            found,
+            dir,
+            target,
+        })
+    }
+
+    /// The stow dir
+    pub fn dir(&self) -> &Setting<PathBuf> {
+        &self.dir
+    }
+
+    /// The directory 'nstow init' links files into
+    pub fn target(&self) -> &Setting<String> {
+        &self.target
+    }
+}
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.path, self.found) {
+            (Some(path), true) => writeln!(f, "# Config file: {}", path.display())?,
+            (Some(path), false) => writeln!(f, "# Config file: {} (not found)", path.display())?,
+            (None, _) => writeln!(f, "# Config file: unable to find the config directory")?,
+        }
+        writeln!(
+            f,
+            "dir: {}  # {}",
+            self.dir.value().display(),
+            self.dir.source()
+        )?;
+        writeln!(
+            f,
+            "target: {}  # {}",
+            self.target.value(),
+            self.target.source()
+        )
+    }
+}
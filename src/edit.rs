@@ -0,0 +1,1576 @@
+use crate::settings::Settings;
+use crate::stow::Stow;
+use crate::vars::parse_variable;
+use anyhow::{bail, Context, Result};
+use log::{error, info};
+use std::fs;
+use std::ops::Range;
+
+/// Top level sections that can be edited
+const STOW_KEY: &str = "stow";
+const VARS_KEY: &str = "vars";
+const PACKAGES_KEY: &str = "packages";
+/// Keys of a link entry
+const SRC_KEY: &str = "src";
+/// A directory node with options keeps its contents under this key
+const NODE_STOW_KEY: &str = "stow";
+
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+fn is_blank(line: &str) -> bool {
+    line.trim().is_empty()
+}
+
+fn is_comment(line: &str) -> bool {
+    line.trim_start().starts_with('#')
+}
+
+fn is_content(line: &str) -> bool {
+    !is_blank(line) && !is_comment(line)
+}
+
+/// Check if text, with its indent removed, starts a list item
+fn is_item(text: &str) -> bool {
+    text == "-" || text.starts_with("- ")
+}
+
+/// Split a source path into its components, so 'a/b' matches 'b' in directory 'a'
+fn components(path: &str) -> Vec<&str> {
+    path.split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect()
+}
+
+/// How a scalar was quoted in the stowfile
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Quote {
+    Plain,
+    Single,
+    Double,
+}
+impl Quote {
+    fn of(token: &str) -> Self {
+        if token.starts_with('"') {
+            Quote::Double
+        } else if token.starts_with('\'') {
+            Quote::Single
+        } else {
+            Quote::Plain
+        }
+    }
+
+    /// Write a value in this style. Plain values that YAML would read as something else are
+    /// quoted
+    fn format(&self, value: &str) -> Result<String> {
+        Ok(match self {
+            Quote::Double => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+            Quote::Single => format!("'{}'", value.replace('\'', "''")),
+            Quote::Plain => {
+                let value = serde_yaml::Value::String(value.to_string());
+                serde_yaml::to_string(&value)?.trim_end().to_string()
+            }
+        })
+    }
+}
+
+/// Where the scalar starting at or after `from` is in the line, leaving out any comment after it.
+/// Nothing is returned when there is no scalar, or the value is a flow collection or block scalar
+fn scalar_range(line: &str, from: usize) -> Option<Range<usize>> {
+    let start = from + (line[from..].len() - line[from..].trim_start().len());
+    let text = &line[start..];
+    let length = match text.chars().next()? {
+        '#' | '[' | '{' | '|' | '>' => return None,
+        '"' => {
+            let mut escaped = false;
+            let close = text.char_indices().skip(1).find(|(_, c)| {
+                let close = !escaped && *c == '"';
+                escaped = !escaped && *c == '\\';
+                close
+            })?;
+            close.0 + 1
+        }
+        '\'' => {
+            let bytes = text.as_bytes();
+            let mut i = 1;
+            loop {
+                match bytes.get(i)? {
+                    b'\'' if bytes.get(i + 1) == Some(&b'\'') => i += 2,
+                    b'\'' => break i + 1,
+                    _ => i += 1,
+                }
+            }
+        }
+        _ => {
+            let end = text.find(" #").unwrap_or(text.len());
+            text[..end].trim_end().len()
+        }
+    };
+    Some(start..start + length)
+}
+
+/// The string a scalar token stands for
+fn decode_scalar(token: &str) -> Option<String> {
+    match serde_yaml::from_str(token).ok()? {
+        serde_yaml::Value::String(value) => Some(value),
+        serde_yaml::Value::Bool(_) | serde_yaml::Value::Number(_) => Some(token.to_string()),
+        _ => None,
+    }
+}
+
+/// Split 'KEY: VALUE' text into the key and the offset of the value
+fn split_key(text: &str) -> Option<(String, usize)> {
+    if text.starts_with(['"', '\'']) {
+        let range = scalar_range(text, 0)?;
+        let key = decode_scalar(&text[range.clone()])?;
+        let after = &text[range.end..];
+        if after.starts_with(':') && (after.len() == 1 || after[1..].starts_with(' ')) {
+            return Some((key, range.end + 1));
+        }
+        return None;
+    }
+    if text.starts_with(['-', '[', '{', '#']) {
+        return None;
+    }
+    for (i, c) in text.char_indices() {
+        if c == '#' && text[..i].ends_with(' ') {
+            return None;
+        }
+        if c == ':' && text[i + 1..].chars().next().is_none_or(|next| next == ' ') {
+            let key = text[..i].trim_end();
+            return (!key.is_empty()).then(|| (key.to_string(), i + 1));
+        }
+    }
+    None
+}
+
+/// A key or list item, from the comments directly above it to its last line of content and any
+/// comments indented under that
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    start: usize,
+    // The line with the key or '-'
+    line: usize,
+    end: usize,
+}
+
+/// A mapping key and where its value starts
+#[derive(Debug, Clone)]
+struct Key {
+    name: String,
+    line: usize,
+    column: usize,
+    // Byte offset in the line just past the ':'
+    value: usize,
+}
+
+/// A link entry or a directory node found in the 'stow' tree
+#[derive(Debug)]
+struct Found {
+    span: Span,
+    // The entry's 'src' key, or the lines of the directory node's contents
+    src: Option<Key>,
+    contents: Range<usize>,
+    // Paths of the directory nodes the entry is in, outermost first
+    dirs: Vec<Vec<String>>,
+    // The outermost of those nodes with options of its own, like tags or vars
+    options_dir: Option<Vec<String>>,
+    // The package the entry is in
+    package: Option<String>,
+}
+
+/// An item of the 'vars' list, either a 'KEY=VALUE' string or a 'KEY: VALUE' mapping
+#[derive(Debug)]
+enum VarItem {
+    Text { line: usize, offset: usize },
+    Mapping(Key),
+}
+
+/// A stowfile as lines of text, for making changes without losing comments, ordering, or quoting.
+/// Only block style collections can be edited. The text is never reformatted, so lines that are
+/// not part of a change stay as they are
+#[derive(Debug)]
+pub struct Document {
+    lines: Vec<String>,
+}
+impl Document {
+    pub fn parse(text: &str) -> Result<Self> {
+        serde_yaml::from_str::<serde_yaml::Value>(text)?;
+        Ok(Document {
+            lines: text.lines().map(str::to_string).collect(),
+        })
+    }
+
+    /// The edited text. Changes that would leave the stowfile unreadable are refused
+    pub fn into_text(self) -> Result<String> {
+        let mut text = self.lines.join("\n");
+        text.push('\n');
+        if let Err(e) = serde_yaml::from_str::<serde_yaml::Value>(&text) {
+            bail!(
+                "Editing the stowfile would make it invalid YAML ({}). Make the change by hand",
+                e
+            );
+        }
+        Ok(text)
+    }
+
+    fn value(&self) -> Result<serde_yaml::Value> {
+        let text = self.lines.join("\n");
+        Ok(serde_yaml::from_str(&text)?)
+    }
+
+    /// Keys at the top level of the file
+    fn top_level_keys(&self) -> Vec<Key> {
+        self.lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| is_content(line) && indent(line) == 0)
+            .filter_map(|(line, text)| {
+                let (name, value) = split_key(text)?;
+                Some(Key {
+                    name,
+                    line,
+                    column: 0,
+                    value,
+                })
+            })
+            .collect()
+    }
+
+    fn section(&self, name: &str) -> Option<Key> {
+        self.top_level_keys()
+            .into_iter()
+            .find(|key| key.name == name)
+    }
+
+    /// Check if the value is written on the same line as the key, like 'key: value' or 'key: []'
+    fn has_inline_value(&self, key: &Key) -> bool {
+        let rest = self.lines[key.line][key.value..].trim();
+        !rest.is_empty() && !rest.starts_with('#')
+    }
+
+    /// The lines holding a key's block value. A list may be at the same indent as its key
+    fn value_lines(&self, key: &Key, limit: usize) -> Range<usize> {
+        let start = key.line + 1;
+        let mut end = start;
+        for i in start..limit {
+            let line = &self.lines[i];
+            if !is_content(line) {
+                continue;
+            }
+            let line_indent = indent(line);
+            if line_indent > key.column || (line_indent == key.column && is_item(line.trim_start()))
+            {
+                end = i + 1;
+            } else {
+                break;
+            }
+        }
+        // Comments indented under the value after its last line are part of it
+        while end < limit && is_comment(&self.lines[end]) && indent(&self.lines[end]) > key.column {
+            end += 1;
+        }
+        start..end
+    }
+
+    /// The keys or items of a block collection, and whether it is a list
+    fn children(&self, lines: Range<usize>) -> (Vec<Span>, bool) {
+        let Some(first) = lines.clone().find(|&i| is_content(&self.lines[i])) else {
+            return (Vec::new(), true);
+        };
+        let column = indent(&self.lines[first]);
+        let list = is_item(self.lines[first].trim_start());
+        let heads: Vec<usize> = (first..lines.end)
+            .filter(|&i| {
+                let line = &self.lines[i];
+                is_content(line) && indent(line) == column && is_item(line.trim_start()) == list
+            })
+            .collect();
+
+        let mut spans: Vec<Span> = Vec::new();
+        for (n, &line) in heads.iter().enumerate() {
+            let next = heads.get(n + 1).copied().unwrap_or(lines.end);
+            let mut end = (line..next)
+                .rev()
+                .find(|&i| is_content(&self.lines[i]))
+                .unwrap_or(line)
+                + 1;
+            // Comments indented under the item after its last line are part of it
+            while end < next && is_comment(&self.lines[end]) && indent(&self.lines[end]) > column {
+                end += 1;
+            }
+            // Comments directly above an item belong to it, unless they are indented like the
+            // contents of the item before it
+            let lower = spans.last().map(|span| span.end).unwrap_or(lines.start);
+            let mut start = line;
+            while start > lower
+                && is_comment(&self.lines[start - 1])
+                && indent(&self.lines[start - 1]) <= column
+            {
+                start -= 1;
+            }
+            spans.push(Span { start, line, end });
+        }
+        (spans, list)
+    }
+
+    /// The keys of a list item that is a mapping
+    fn item_keys(&self, span: Span) -> Vec<Key> {
+        let line = &self.lines[span.line];
+        let dash = indent(line);
+        let after_dash = &line[dash + 1..];
+        let inner = dash + 1 + (after_dash.len() - after_dash.trim_start().len());
+        let mut keys = Vec::new();
+        if let Some((name, value)) = split_key(&line[inner..]) {
+            keys.push(Key {
+                name,
+                line: span.line,
+                column: inner,
+                value: inner + value,
+            });
+        }
+        // With nothing after the '-', the keys start on the next line
+        let column = if inner == line.len() {
+            (span.line + 1..span.end)
+                .find(|&i| is_content(&self.lines[i]))
+                .map(|i| indent(&self.lines[i]))
+                .unwrap_or(inner)
+        } else {
+            inner
+        };
+        for i in span.line + 1..span.end {
+            let text = &self.lines[i];
+            if is_content(text) && indent(text) == column && !is_item(text.trim_start()) {
+                if let Some((name, value)) = split_key(&text[column..]) {
+                    keys.push(Key {
+                        name,
+                        line: i,
+                        column,
+                        value: column + value,
+                    });
+                }
+            }
+        }
+        keys
+    }
+
+    /// The key of an entry in a block mapping
+    fn mapping_key(&self, span: Span) -> Option<Key> {
+        let line = &self.lines[span.line];
+        let column = indent(line);
+        let (name, value) = split_key(&line[column..])?;
+        Some(Key {
+            name,
+            line: span.line,
+            column,
+            value: column + value,
+        })
+    }
+
+    fn scalar(&self, line: usize, offset: usize) -> Option<String> {
+        let range = scalar_range(&self.lines[line], offset)?;
+        decode_scalar(&self.lines[line][range])
+    }
+
+    /// Replace a scalar, keeping its quoting style and any comment after it
+    fn set_scalar(&mut self, line: usize, offset: usize, value: &str) -> Result<()> {
+        let Some(range) = scalar_range(&self.lines[line], offset) else {
+            bail!(
+                "Unable to edit line {} of the stowfile. Make the change by hand",
+                line + 1
+            );
+        };
+        let quote = Quote::of(&self.lines[line][range.clone()]);
+        self.lines[line].replace_range(range, &quote.format(value)?);
+        Ok(())
+    }
+
+    /// The lines of a 'stow' value, a directory node, or a package
+    fn node_contents(&self, lines: Range<usize>) -> Range<usize> {
+        let (children, list) = self.children(lines.clone());
+        if list {
+            return lines;
+        }
+        let options = children
+            .iter()
+            .filter_map(|child| self.mapping_key(*child))
+            .find(|key| key.name == NODE_STOW_KEY);
+        match options {
+            Some(key) => self.value_lines(&key, lines.end),
+            None => lines,
+        }
+    }
+
+    /// Look for an entry, or a directory node when `dir` is set, by its path in the tree
+    fn search(
+        &self,
+        lines: Range<usize>,
+        path: &[&str],
+        dirs: &[Vec<String>],
+        options_dir: Option<&Vec<String>>,
+        dir: bool,
+    ) -> Option<Found> {
+        let contents = self.node_contents(lines);
+        let (children, list) = self.children(contents.clone());
+        for child in children {
+            let node = if list {
+                let keys = self.item_keys(child);
+                if let Some(src) = keys.iter().find(|key| key.name == SRC_KEY) {
+                    let value = self.scalar(src.line, src.value);
+                    if !dir && value.as_deref().map(components).as_deref() == Some(path) {
+                        return Some(Found {
+                            span: child,
+                            src: Some(src.clone()),
+                            contents: child.line..child.end,
+                            dirs: dirs.to_vec(),
+                            options_dir: options_dir.cloned(),
+                            package: None,
+                        });
+                    }
+                    continue;
+                }
+                match keys.as_slice() {
+                    [key] => key.clone(),
+                    _ => continue,
+                }
+            } else {
+                match self.mapping_key(child) {
+                    Some(key) => key,
+                    None => continue,
+                }
+            };
+
+            let name = components(&node.name);
+            if !path.starts_with(&name) || name.is_empty() {
+                continue;
+            }
+            let value = self.value_lines(&node, child.end);
+            if path.len() == name.len() {
+                if dir {
+                    return Some(Found {
+                        span: child,
+                        src: None,
+                        contents: self.node_contents(value),
+                        dirs: dirs.to_vec(),
+                        options_dir: options_dir.cloned(),
+                        package: None,
+                    });
+                }
+                continue;
+            }
+            let mut node_dirs = dirs.to_vec();
+            let mut node_path = dirs.last().cloned().unwrap_or_default();
+            node_path.extend(name.iter().map(|name| name.to_string()));
+            // A node's contents are under its own 'stow' key when it has options
+            let has_options = self.node_contents(value.clone()) != value;
+            let node_options_dir = options_dir.or(Some(&node_path).filter(|_| has_options));
+            node_dirs.push(node_path.clone());
+            let found = self.search(
+                value,
+                &path[name.len()..],
+                &node_dirs,
+                node_options_dir,
+                dir,
+            );
+            if found.is_some() {
+                return found;
+            }
+        }
+        None
+    }
+
+    /// Search the top level 'stow' section, then each package's
+    fn find(&self, path: &str, dir: bool) -> Option<Found> {
+        let path = components(path);
+        if path.is_empty() {
+            return None;
+        }
+        if let Some(stow) = self.section(STOW_KEY) {
+            let lines = self.value_lines(&stow, self.lines.len());
+            if let Some(found) = self.search(lines, &path, &[], None, dir) {
+                return Some(found);
+            }
+        }
+        let packages = self.section(PACKAGES_KEY)?;
+        let lines = self.value_lines(&packages, self.lines.len());
+        let (children, _) = self.children(lines);
+        children.into_iter().find_map(|child| {
+            let package = self.mapping_key(child)?;
+            let options = self.value_lines(&package, child.end);
+            let (keys, _) = self.children(options.clone());
+            let stow = keys
+                .into_iter()
+                .filter_map(|key| self.mapping_key(key))
+                .find(|key| key.name == NODE_STOW_KEY)?;
+            let lines = self.value_lines(&stow, options.end);
+            let mut found = self.search(lines, &path, &[], None, dir)?;
+            found.package = Some(package.name);
+            Some(found)
+        })
+    }
+
+    /// Set a top level key to a scalar value. A missing key is added before the first key in the
+    /// file, above any comments written against that key
+    pub fn set_top_level(&mut self, name: &str, value: &serde_yaml::Value) -> Result<()> {
+        let text = serde_yaml::to_string(value)?.trim_end().to_string();
+        if let Some(key) = self.section(name) {
+            let range = Some(key.value)
+                .filter(|_| self.has_inline_value(&key))
+                .and_then(|value| scalar_range(&self.lines[key.line], value));
+            let Some(range) = range else {
+                bail!(
+                    "The stowfile's '{}' key is not a scalar. Make the change by hand",
+                    name
+                );
+            };
+            self.lines[key.line].replace_range(range, &text);
+            return Ok(());
+        }
+
+        let line = match self.top_level_keys().first() {
+            Some(first) => {
+                let mut line = first.line;
+                while line > 0 && is_comment(&self.lines[line - 1]) {
+                    line -= 1;
+                }
+                line
+            }
+            None => self.lines.len(),
+        };
+        self.lines
+            .splice(line..line, [format!("{}: {}", name, text), String::new()]);
+        Ok(())
+    }
+
+    pub fn has_entry(&self, src: &str) -> bool {
+        self.find(src, false).is_some()
+    }
+
+    /// Remove lines, along with a blank line so the lines around them keep their spacing
+    fn remove_lines(&mut self, lines: Range<usize>) {
+        let blank_before = lines.start == 0
+            || is_blank(&self.lines[lines.start - 1])
+            || self.lines[lines.start - 1].trim_end().ends_with(':');
+        let blank_after = self.lines.get(lines.end).is_some_and(|line| is_blank(line));
+        let end = if blank_before && blank_after {
+            lines.end + 1
+        } else {
+            lines.end
+        };
+        self.lines.drain(lines.start..end);
+    }
+
+    /// Add a section for a key missing from the file. Variables go before the 'stow' section,
+    /// anything else goes at the end
+    fn add_section(&mut self, name: &str) -> Key {
+        let stow = self.section(STOW_KEY).filter(|_| name == VARS_KEY);
+        let line = match stow {
+            Some(stow) => {
+                // Keep the comments above 'stow:' with it
+                let mut line = stow.line;
+                while line > 0 && is_comment(&self.lines[line - 1]) {
+                    line -= 1;
+                }
+                self.lines
+                    .splice(line..line, [format!("{}:", name), String::new()]);
+                line
+            }
+            None => {
+                self.lines.push(format!("{}:", name));
+                self.lines.len() - 1
+            }
+        };
+        Key {
+            name: name.to_string(),
+            line,
+            column: 0,
+            value: name.len() + 1,
+        }
+    }
+
+    /// Add an item, written with no indent, to the end of a top level list
+    fn append_item(&mut self, section: &str, item: Vec<String>) -> Result<()> {
+        let key = match self.section(section) {
+            Some(key) => key,
+            None => self.add_section(section),
+        };
+        if self.has_inline_value(&key) {
+            bail!(
+                "The stowfile's '{}' section is not a block style list. Make the change by hand",
+                section
+            );
+        }
+        let (children, list) = self.children(self.value_lines(&key, self.lines.len()));
+        if !list {
+            bail!(
+                "The stowfile's '{}' section is not a list. Make the change by hand",
+                section
+            );
+        }
+        let column = children
+            .first()
+            .map(|child| indent(&self.lines[child.line]))
+            .unwrap_or(2);
+        // Keep blank lines between items if the list already has them
+        let spaced = children.windows(2).any(|pair| pair[1].start > pair[0].end);
+        let at = children
+            .last()
+            .map(|child| child.end)
+            .unwrap_or(key.line + 1);
+
+        let pad = " ".repeat(column);
+        let mut lines = Vec::new();
+        if spaced {
+            lines.push(String::new());
+        }
+        lines.extend(item.into_iter().map(|line| {
+            if line.is_empty() {
+                line
+            } else {
+                format!("{}{}", pad, line)
+            }
+        }));
+        self.lines.splice(at..at, lines);
+        Ok(())
+    }
+
+    /// Add a link entry to the end of the top level 'stow' list
+    pub fn add_entry(&mut self, src: &str, links: &[String]) -> Result<()> {
+        if self.has_entry(src) {
+            bail!("The stowfile already has an entry for '{}'", src);
+        }
+        let mut item = vec![
+            format!("- {}: {}", SRC_KEY, Quote::Plain.format(src)?),
+            "  links:".to_string(),
+        ];
+        for link in links {
+            item.push(format!("    - {}", Quote::Double.format(link)?));
+        }
+        self.append_item(STOW_KEY, item)
+    }
+
+    /// Remove an entry. Directory nodes left empty are removed too, since an empty node would
+    /// make the stowfile invalid
+    pub fn remove_entry(&mut self, src: &str) -> Result<()> {
+        let Some(found) = self.find(src, false) else {
+            bail!("The stowfile has no entry for '{}'", src);
+        };
+        self.remove_lines(found.span.start..found.span.end);
+        for dir in found.dirs.iter().rev() {
+            let Some(node) = self.find(&dir.join("/"), true) else {
+                break;
+            };
+            if !self.children(node.contents).0.is_empty() {
+                break;
+            }
+            self.remove_lines(node.span.start..node.span.end);
+        }
+        Ok(())
+    }
+
+    /// Change an entry's source. The entry stays where it is when the new source is in the same
+    /// directory node, otherwise it moves to the end of the top level 'stow' list. Entries that
+    /// would lose the package or node options they inherit can't leave their node
+    pub fn move_entry(&mut self, from: &str, to: &str) -> Result<()> {
+        let Some(found) = self.find(from, false) else {
+            bail!("The stowfile has no entry for '{}'", from);
+        };
+        if self.has_entry(to) {
+            bail!("The stowfile already has an entry for '{}'", to);
+        }
+        let Some(src) = &found.src else {
+            bail!("'{}' is not a link entry", from);
+        };
+        let to_path = components(to);
+        let dir = found.dirs.last().cloned().unwrap_or_default();
+        if to_path.len() > dir.len()
+            && to_path.starts_with(&dir.iter().map(String::as_str).collect::<Vec<_>>())
+        {
+            return self.set_scalar(src.line, src.value, &to_path[dir.len()..].join("/"));
+        }
+        if let Some(package) = &found.package {
+            bail!(
+                "'{}' is in package '{}', so it can only be moved within '{}'. Make the change by hand",
+                from,
+                package,
+                dir.join("/")
+            );
+        }
+        if let Some(options_dir) = &found.options_dir {
+            bail!(
+                "'{}' would lose the options of directory '{}', so it can only be moved within '{}'. Make the change by hand",
+                from,
+                options_dir.join("/"),
+                dir.join("/")
+            );
+        }
+
+        // Take the entry out of its node, dropping its indent
+        let column = indent(&self.lines[found.span.line]);
+        let mut item: Vec<String> = self.lines[found.span.start..found.span.end]
+            .iter()
+            .map(|line| {
+                line.get(column.min(indent(line))..)
+                    .unwrap_or("")
+                    .to_string()
+            })
+            .collect();
+        let src_line = src.line - found.span.start;
+        let Some(range) = scalar_range(&item[src_line], src.value - column) else {
+            bail!(
+                "Unable to edit the source of '{}'. Make the change by hand",
+                from
+            );
+        };
+        let quote = Quote::of(&item[src_line][range.clone()]);
+        item[src_line].replace_range(range, &quote.format(&to_path.join("/"))?);
+
+        self.remove_entry(from)?;
+        self.append_item(STOW_KEY, item)
+    }
+
+    /// Find a variable in the top level 'vars' list
+    fn find_var(&self, name: &str) -> Option<(Span, VarItem)> {
+        let vars = self.section(VARS_KEY)?;
+        let (children, list) = self.children(self.value_lines(&vars, self.lines.len()));
+        if !list {
+            return None;
+        }
+        children.into_iter().find_map(|child| {
+            if let Some(key) = self.item_keys(child).into_iter().next() {
+                return (key.name == name && key.line == child.line)
+                    .then_some((child, VarItem::Mapping(key)));
+            }
+            let line = &self.lines[child.line];
+            let offset = indent(line) + 1;
+            let var = self.scalar(child.line, offset)?;
+            let (key, _) = var.split_once('=')?;
+            (key.trim() == name).then_some((
+                child,
+                VarItem::Text {
+                    line: child.line,
+                    offset,
+                },
+            ))
+        })
+    }
+
+    /// Set a variable in the top level 'vars' list, replacing its value if it is already there
+    pub fn set_var(&mut self, name: &str, value: &str) -> Result<()> {
+        match self.find_var(name) {
+            Some((_, VarItem::Text { line, offset })) => {
+                self.set_scalar(line, offset, &format!("{}={}", name, value))
+            }
+            Some((span, VarItem::Mapping(key))) => {
+                if scalar_range(&self.lines[key.line], key.value).is_some() {
+                    return self.set_scalar(key.line, key.value, value);
+                }
+                // A list value is replaced by the new text value
+                let line = format!(
+                    "{}- {}",
+                    " ".repeat(indent(&self.lines[span.line])),
+                    Quote::Plain.format(&format!("{}={}", name, value))?
+                );
+                self.lines.splice(span.line..span.end, [line]);
+                Ok(())
+            }
+            None => self.append_item(
+                VARS_KEY,
+                vec![format!(
+                    "- {}",
+                    Quote::Plain.format(&format!("{}={}", name, value))?
+                )],
+            ),
+        }
+    }
+
+    pub fn remove_var(&mut self, name: &str) -> Result<()> {
+        let Some((span, _)) = self.find_var(name) else {
+            bail!("The stowfile's 'vars' section has no variable '{}'", name);
+        };
+        self.remove_lines(span.start..span.end);
+        Ok(())
+    }
+}
+
+/// Spaces per level of indent in a formatted stowfile
+const INDENT: usize = 2;
+
+/// The part of a stowfile a block is in, for the parts that are formatted specially
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Section {
+    Top,
+    // A 'stow' list, or a directory node or package with its options
+    Stow,
+    Vars,
+    Packages,
+    Other,
+}
+impl Section {
+    fn of_key(&self, key: &str) -> Section {
+        match (self, key) {
+            (Section::Top, STOW_KEY) | (Section::Stow, NODE_STOW_KEY) => Section::Stow,
+            (Section::Top | Section::Stow, VARS_KEY) => Section::Vars,
+            (Section::Top, PACKAGES_KEY) => Section::Packages,
+            (Section::Packages, _) => Section::Stow,
+            (Section::Stow, "hooks" | "tags" | "requires") => Section::Other,
+            // Directories may be a mapping of their names to their contents
+            (Section::Stow, _) => Section::Stow,
+            _ => Section::Other,
+        }
+    }
+}
+
+/// A formatted key or list item, along with the comments in front of it and any blank lines
+/// between them
+struct Unit {
+    blank_before: bool,
+    lines: Vec<String>,
+    // The source or directory name of a 'stow' list item, for sorting
+    name: Option<String>,
+}
+
+/// Write a variable's value without quotes, unless YAML needs them. Numbers and booleans are left
+/// as they are, since quoting them would make them strings
+fn normalize_scalar(text: &str) -> Result<String> {
+    let Some(range) = scalar_range(text, 0) else {
+        return Ok(text.to_string());
+    };
+    let Ok(serde_yaml::Value::String(value)) = serde_yaml::from_str(&text[range.clone()]) else {
+        return Ok(text.to_string());
+    };
+    let mut normalized = text.to_string();
+    normalized.replace_range(range, &Quote::Plain.format(&value)?);
+    Ok(normalized)
+}
+
+impl Document {
+    /// Rewrite the stowfile with every level indented by two spaces and variables quoted only
+    /// when needed. Comments are kept with the lines they are above, and runs of blank lines
+    /// become one. With `sort`, entries in each 'stow' list are sorted by source
+    pub fn format(&self, sort: bool) -> Result<Document> {
+        let mut lines = self.format_block(0..self.lines.len(), 0, Section::Top, sort)?;
+        while lines.first().is_some_and(|line| line.is_empty()) {
+            lines.remove(0);
+        }
+        let formatted = Document { lines };
+        // Sorting reorders lists, but otherwise the formatted stowfile must mean the same thing
+        if !sort && formatted.value()? != self.value()? {
+            bail!(
+                "Formatting would change the meaning of the stowfile. Please report this as a bug"
+            );
+        }
+        Ok(formatted)
+    }
+
+    /// Copy lines, moving them by the difference between two indents
+    fn shifted(&self, lines: Range<usize>, from: usize, to: usize) -> Vec<String> {
+        self.lines[lines]
+            .iter()
+            .map(|line| {
+                if is_blank(line) {
+                    String::new()
+                } else {
+                    let keep = indent(line).saturating_sub(from);
+                    format!("{}{}", " ".repeat(to + keep), line.trim_start())
+                }
+            })
+            .collect()
+    }
+
+    /// Comments and blank lines that aren't attached to a key or item, moved from one indent to
+    /// another. Blank lines keep their place between the comments, with runs of them made into
+    /// one. Comments indented past the block belonged to the item before them, so they stay
+    /// indented
+    fn loose_lines(&self, lines: Range<usize>, from: usize, to: usize) -> Vec<String> {
+        let mut loose: Vec<String> = Vec::new();
+        for line in &self.lines[lines] {
+            if is_blank(line) {
+                if loose.last().is_none_or(|last| !last.is_empty()) {
+                    loose.push(String::new());
+                }
+            } else {
+                let pad = if indent(line) > from { to + INDENT } else { to };
+                loose.push(format!("{}{}", " ".repeat(pad), line.trim()));
+            }
+        }
+        loose
+    }
+
+    /// Where the comments trailing the item before a gap end, and the gap's own lines begin
+    fn trailing_comments_end(&self, lines: Range<usize>, column: usize) -> usize {
+        lines
+            .clone()
+            .find(|&i| !is_comment(&self.lines[i]) || indent(&self.lines[i]) <= column)
+            .unwrap_or(lines.end)
+    }
+
+    fn format_block(
+        &self,
+        lines: Range<usize>,
+        column: usize,
+        section: Section,
+        sort: bool,
+    ) -> Result<Vec<String>> {
+        let (children, list) = self.children(lines.clone());
+        let old_column = children
+            .first()
+            .map(|child| indent(&self.lines[child.line]))
+            .unwrap_or(column);
+
+        let mut units: Vec<Unit> = Vec::new();
+        let mut previous_end = lines.start;
+        for child in &children {
+            let mut gap = previous_end..child.start;
+            if let Some(previous) = units.last_mut() {
+                let trailing = gap.start..self.trailing_comments_end(gap.clone(), old_column);
+                let comments = self.loose_lines(trailing.clone(), old_column, column);
+                previous.lines.extend(comments);
+                gap.start = trailing.end;
+            }
+            // A blank line before the unit separates it from the one before. Blank lines after
+            // its first comment stay where they are, so detached comments stay detached
+            let mut unit_lines = self.loose_lines(gap, old_column, column);
+            let blank_before = unit_lines.first().is_some_and(|line| line.is_empty());
+            if blank_before {
+                unit_lines.remove(0);
+            }
+            unit_lines.extend(self.shifted(child.start..child.line, old_column, column));
+            let name = if list {
+                self.format_item(*child, column, section, sort, &mut unit_lines)?
+            } else {
+                self.format_key(*child, column, section, sort, &mut unit_lines)?;
+                None
+            };
+            units.push(Unit {
+                blank_before,
+                lines: unit_lines,
+                name,
+            });
+            previous_end = child.end;
+        }
+        let mut tail = self.loose_lines(previous_end..lines.end, old_column, column);
+        while tail.last().is_some_and(|line| line.is_empty()) {
+            tail.pop();
+        }
+
+        let sorted = sort && list && section == Section::Stow;
+        if sorted {
+            // Blank lines separate every item if they separated any
+            let spaced = units.iter().skip(1).any(|unit| unit.blank_before);
+            units.sort_by(|a, b| a.name.cmp(&b.name));
+            for unit in &mut units {
+                unit.blank_before = spaced;
+            }
+        }
+
+        let mut out = Vec::new();
+        for (n, unit) in units.into_iter().enumerate() {
+            if unit.blank_before && n > 0 {
+                out.push(String::new());
+            }
+            out.extend(unit.lines);
+        }
+        out.extend(tail);
+        Ok(out)
+    }
+
+    fn format_key(
+        &self,
+        child: Span,
+        column: usize,
+        section: Section,
+        sort: bool,
+        out: &mut Vec<String>,
+    ) -> Result<()> {
+        let Some(key) = self.mapping_key(child) else {
+            let from = indent(&self.lines[child.line]);
+            out.extend(self.shifted(child.line..child.end, from, column));
+            return Ok(());
+        };
+        out.push(format!(
+            "{}{}",
+            " ".repeat(column),
+            self.lines[key.line][key.column..].trim_end()
+        ));
+        if self.has_inline_value(&key) {
+            out.extend(self.shifted(key.line + 1..child.end, key.column, column));
+        } else {
+            let value = self.value_lines(&key, child.end);
+            out.extend(self.format_block(
+                value,
+                column + INDENT,
+                section.of_key(&key.name),
+                sort,
+            )?);
+        }
+        Ok(())
+    }
+
+    /// Format a list item, returning its name if it is a 'stow' list entry
+    fn format_item(
+        &self,
+        child: Span,
+        column: usize,
+        section: Section,
+        sort: bool,
+        out: &mut Vec<String>,
+    ) -> Result<Option<String>> {
+        let line = &self.lines[child.line];
+        let dash = indent(line);
+        let keys = self.item_keys(child);
+        let pad = " ".repeat(column);
+
+        let Some(first) = keys.first() else {
+            // A scalar, like a 'KEY=VALUE' variable or a link
+            let rest = line[dash + 1..].trim();
+            let rest = if section == Section::Vars {
+                normalize_scalar(rest)?
+            } else {
+                rest.to_string()
+            };
+            out.push(format!("{}- {}", pad, rest).trim_end().to_string());
+            out.extend(self.shifted(child.line + 1..child.end, dash, column));
+            return Ok(None);
+        };
+
+        let entry = keys.iter().find(|key| key.name == SRC_KEY);
+        let name = match entry {
+            Some(src) => self.scalar(src.line, src.value),
+            None => Some(first.name.clone()),
+        };
+        let key_section = match section {
+            Section::Stow if entry.is_none() => Section::Stow,
+            // The elements of a list variable
+            Section::Vars => Section::Vars,
+            _ => Section::Other,
+        };
+
+        let inner = column + INDENT;
+        let mut previous_end = child.line + 1;
+        for (n, key) in keys.iter().enumerate() {
+            if key.line > child.line {
+                out.extend(self.loose_lines(previous_end..key.line, key.column, inner));
+            }
+            let mut text = self.lines[key.line][key.column..].trim_end().to_string();
+            if section == Section::Vars && self.has_inline_value(key) {
+                let value = key.value - key.column;
+                let normalized = normalize_scalar(&text[value..])?;
+                text.replace_range(value.., &normalized);
+            }
+            let prefix = if n == 0 {
+                format!("{}- ", pad)
+            } else {
+                " ".repeat(inner)
+            };
+            out.push(format!("{}{}", prefix, text));
+
+            let limit = keys.get(n + 1).map(|next| next.line).unwrap_or(child.end);
+            if self.has_inline_value(key) {
+                let end = (key.line + 1..limit)
+                    .rev()
+                    .find(|&i| is_content(&self.lines[i]))
+                    .map(|i| i + 1)
+                    .unwrap_or(key.line + 1);
+                out.extend(self.shifted(key.line + 1..end, key.column, inner));
+                previous_end = end;
+            } else {
+                let value = self.value_lines(key, limit);
+                previous_end = value.end.max(key.line + 1);
+                out.extend(self.format_block(value, inner + INDENT, key_section, sort)?);
+            }
+        }
+        out.extend(self.loose_lines(previous_end..child.end, first.column, inner));
+        Ok(name)
+    }
+}
+
+/// Write an edited stowfile and make sure it still loads. If it doesn't, the original text is put
+/// back
+pub fn write_stowfile(settings: &Settings, original: &str, updated: &str) -> Result<()> {
+    let path = settings.stowfile_path();
+    let written = fs::write(path, updated)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| Stow::with_settings(settings).map(|_| ()));
+    if let Err(e) = written {
+        restore_stowfile(settings, original);
+        return Err(e).context("Unable to change the stowfile");
+    }
+    Ok(())
+}
+
+/// Put back the stowfile's text from before a change, when a later step fails
+pub fn restore_stowfile(settings: &Settings, original: &str) {
+    let path = settings.stowfile_path();
+    if let Err(e) = fs::write(path, original) {
+        error!("Unable to restore {:?}: {}", path, e);
+    }
+}
+
+/// Make a change to the stowfile, described for log messages
+pub fn change_stowfile(
+    settings: &Settings,
+    description: &str,
+    change: impl FnOnce(&mut Document) -> Result<()>,
+) -> Result<()> {
+    let path = settings.stowfile_path();
+    let original = fs::read_to_string(path)?;
+    let mut stowfile = Document::parse(&original)?;
+    change(&mut stowfile)?;
+    let updated = stowfile.into_text()?;
+    if settings.link_settings().dry_run() {
+        info!("Pretending to edit {:?} to {}", path, description);
+        return Ok(());
+    }
+    info!("Editing {:?} to {}", path, description);
+    write_stowfile(settings, &original, &updated)
+}
+
+/// Set a variable in the stowfile from 'KEY=VALUE' text
+pub fn set_var(settings: &Settings, var: &str) -> Result<()> {
+    let (key, value) = parse_variable(var).with_context(|| format!("Unable to set '{}'", var))?;
+    change_stowfile(
+        settings,
+        &format!("set '{}' to {:?}", key, value),
+        |stowfile| stowfile.set_var(&key, &value),
+    )
+}
+
+pub fn unset_var(settings: &Settings, key: &str) -> Result<()> {
+    change_stowfile(settings, &format!("remove '{}'", key), |stowfile| {
+        stowfile.remove_var(key)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(text: &str, change: impl FnOnce(&mut Document) -> Result<()>) -> String {
+        let mut document = Document::parse(text).unwrap();
+        change(&mut document).unwrap();
+        document.into_text().unwrap()
+    }
+
+    const NESTED: &str = "\
+# My dotfiles
+stow:
+  - src: 'a file'
+    links:
+      - \"${HOME}/a\" # trailing comment
+
+  # Config for bash
+  - bash:
+      - src: bashrc
+        links:
+          - ${HOME}/.bashrc
+      - src: profile
+        links:
+          - ${HOME}/.profile
+
+packages:
+  sway:
+    stow:
+      - sway:
+          - src: config
+            links:
+              - \"${XDG_CONFIG_HOME}/sway/config\"
+";
+
+    #[test]
+    fn add_entry_appends_to_the_stow_list() {
+        let edited = edit(NESTED, |doc| {
+            doc.add_entry("vim/vimrc", &["${HOME}/.vimrc".to_string()])
+        });
+        let expected = NESTED.replace(
+            "          - ${HOME}/.profile\n",
+            "          - ${HOME}/.profile\n\n  - src: vim/vimrc\n    links:\n      - \"${HOME}/.vimrc\"\n",
+        );
+        assert_eq!(edited, expected);
+    }
+
+    #[test]
+    fn add_entry_refuses_duplicates() {
+        let mut doc = Document::parse(NESTED).unwrap();
+        assert!(doc.add_entry("bash/bashrc", &[]).is_err());
+        assert!(doc.add_entry("sway/config", &[]).is_err());
+    }
+
+    #[test]
+    fn add_entry_creates_the_stow_section() {
+        let edited = edit("version: 1\n", |doc| {
+            doc.add_entry("a", &["${HOME}/a".to_string()])
+        });
+        assert_eq!(
+            edited,
+            "version: 1\nstow:\n  - src: a\n    links:\n      - \"${HOME}/a\"\n"
+        );
+    }
+
+    #[test]
+    fn remove_entry_in_a_node() {
+        let edited = edit(NESTED, |doc| doc.remove_entry("bash/bashrc"));
+        let expected = NESTED.replace(
+            "      - src: bashrc\n        links:\n          - ${HOME}/.bashrc\n",
+            "",
+        );
+        assert_eq!(edited, expected);
+    }
+
+    #[test]
+    fn remove_entry_removes_emptied_nodes() {
+        let edited = edit(NESTED, |doc| {
+            doc.remove_entry("bash/bashrc")?;
+            doc.remove_entry("bash/profile")
+        });
+        // The node's comment goes with it
+        let start = NESTED.find("  # Config for bash").unwrap();
+        let end = NESTED.find("packages:").unwrap();
+        let expected = format!("{}{}", &NESTED[..start], &NESTED[end..]);
+        assert_eq!(edited, expected);
+    }
+
+    #[test]
+    fn remove_entry_in_a_package() {
+        let edited = edit(NESTED, |doc| doc.remove_entry("sway/config"));
+        assert!(
+            edited.ends_with("packages:\n  sway:\n    stow:\n"),
+            "{}",
+            edited
+        );
+        assert!(edited.starts_with(&NESTED[..NESTED.find("packages:").unwrap()]));
+    }
+
+    #[test]
+    fn remove_entry_keeps_other_comments() {
+        let edited = edit(NESTED, |doc| doc.remove_entry("a file"));
+        assert_eq!(
+            edited,
+            NESTED.replace(
+                "  - src: 'a file'\n    links:\n      - \"${HOME}/a\" # trailing comment\n\n",
+                ""
+            )
+        );
+    }
+
+    #[test]
+    fn move_entry_within_a_node_renames_it_in_place() {
+        let edited = edit(NESTED, |doc| doc.move_entry("bash/bashrc", "bash/rc"));
+        assert_eq!(edited, NESTED.replace("src: bashrc", "src: rc"));
+
+        let edited = edit(NESTED, |doc| doc.move_entry("a file", "b file"));
+        assert_eq!(edited, NESTED.replace("src: 'a file'", "src: 'b file'"));
+    }
+
+    #[test]
+    fn move_entry_in_a_package() {
+        let edited = edit(NESTED, |doc| doc.move_entry("sway/config", "sway/main"));
+        assert_eq!(edited, NESTED.replace("src: config", "src: main"));
+    }
+
+    #[test]
+    fn move_entry_to_another_node_appends_it_to_the_stow_list() {
+        let edited = edit(NESTED, |doc| doc.move_entry("bash/profile", "sh/profile"));
+        let expected = NESTED
+            .replace(
+                "      - src: profile\n        links:\n          - ${HOME}/.profile\n",
+                "",
+            )
+            .replace(
+                "          - ${HOME}/.bashrc\n",
+                "          - ${HOME}/.bashrc\n\n  - src: sh/profile\n    links:\n      - ${HOME}/.profile\n",
+            );
+        assert_eq!(edited, expected);
+    }
+
+    #[test]
+    fn move_entry_refuses_to_leave_a_package() {
+        // Outside of 'sway' the entry would no longer be stowed with the package
+        let mut doc = Document::parse(NESTED).unwrap();
+        let error = doc
+            .move_entry("sway/config", "wm/config")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("package 'sway'"), "{}", error);
+        assert_eq!(doc.into_text().unwrap(), NESTED);
+    }
+
+    #[test]
+    fn move_entry_refuses_to_leave_a_node_with_options() {
+        let text = "\
+stow:
+  - bash:
+      tags: [shell]
+      stow:
+        - rc:
+            - src: bashrc
+              links:
+                - ${HOME}/.bashrc
+";
+        let mut doc = Document::parse(text).unwrap();
+        let error = doc
+            .move_entry("bash/rc/bashrc", "zsh/bashrc")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("directory 'bash'"), "{}", error);
+
+        let edited = edit(text, |doc| doc.move_entry("bash/rc/bashrc", "bash/rc/rc"));
+        assert_eq!(edited, text.replace("src: bashrc", "src: rc"));
+    }
+
+    #[test]
+    fn move_entry_refuses_existing_and_missing_sources() {
+        let mut doc = Document::parse(NESTED).unwrap();
+        assert!(doc.move_entry("bash/bashrc", "bash/profile").is_err());
+        assert!(doc.move_entry("bash/missing", "bash/other").is_err());
+    }
+
+    const VARS: &str = "\
+vars:
+  # The editor
+  - EDITOR=vim
+  - \"TERM=xterm # not a comment\"
+  - SHELLS: [bash, zsh]
+  - BROWSER: firefox # the browser
+  - LIST:
+      - a
+      - b
+
+stow: []
+";
+
+    #[test]
+    fn set_var_text_form() {
+        let edited = edit(VARS, |doc| doc.set_var("EDITOR", "nvim"));
+        assert_eq!(edited, VARS.replace("EDITOR=vim", "EDITOR=nvim"));
+
+        // Quotes are kept
+        let edited = edit(VARS, |doc| doc.set_var("TERM", "kitty"));
+        assert_eq!(
+            edited,
+            VARS.replace("\"TERM=xterm # not a comment\"", "\"TERM=kitty\"")
+        );
+    }
+
+    #[test]
+    fn set_var_mapping_form() {
+        let edited = edit(VARS, |doc| doc.set_var("BROWSER", "qutebrowser"));
+        assert_eq!(
+            edited,
+            VARS.replace("BROWSER: firefox", "BROWSER: qutebrowser")
+        );
+    }
+
+    #[test]
+    fn set_var_list_form() {
+        let edited = edit(VARS, |doc| doc.set_var("LIST", "c"));
+        assert_eq!(
+            edited,
+            VARS.replace("  - LIST:\n      - a\n      - b\n", "  - LIST=c\n")
+        );
+
+        let edited = edit(VARS, |doc| doc.set_var("SHELLS", "fish"));
+        assert_eq!(
+            edited,
+            VARS.replace("  - SHELLS: [bash, zsh]\n", "  - SHELLS=fish\n")
+        );
+    }
+
+    #[test]
+    fn set_var_adds_new_variables() {
+        let edited = edit(VARS, |doc| doc.set_var("PAGER", "less -R"));
+        assert_eq!(
+            edited,
+            VARS.replace("      - b\n", "      - b\n  - PAGER=less -R\n")
+        );
+
+        let edited = edit("# Links\nstow: []\n", |doc| doc.set_var("A", "a: b"));
+        assert_eq!(edited, "vars:\n  - 'A=a: b'\n\n# Links\nstow: []\n");
+    }
+
+    #[test]
+    fn remove_var() {
+        // The comment above a variable goes with it
+        let edited = edit(VARS, |doc| doc.remove_var("EDITOR"));
+        assert_eq!(edited, VARS.replace("  # The editor\n  - EDITOR=vim\n", ""));
+
+        let edited = edit(VARS, |doc| doc.remove_var("LIST"));
+        assert_eq!(
+            edited,
+            VARS.replace("  - LIST:\n      - a\n      - b\n", "")
+        );
+
+        assert!(Document::parse(VARS).unwrap().remove_var("PAGER").is_err());
+    }
+
+    fn format(text: &str, sort: bool) -> String {
+        let document = Document::parse(text).unwrap();
+        document.format(sort).unwrap().into_text().unwrap()
+    }
+
+    const MESSY: &str = "\
+# My dotfiles
+
+
+vars:
+    - \"EDITOR=vim\"
+    - 'PAGER=less -R'
+    - \"MSG=a: b\"
+    - LIST:
+        - 'x'
+stow:
+    - src: zsh
+      links:
+          - ${HOME}/.zshrc
+        # About zsh
+
+
+    # Config for bash
+    - bash:
+            - src: bashrc
+              links:
+                  - ${HOME}/.bashrc
+    - src: alacritty
+      links:
+          - ${HOME}/.alacritty.yml
+";
+
+    const FORMATTED: &str = "\
+# My dotfiles
+
+vars:
+  - EDITOR=vim
+  - PAGER=less -R
+  - 'MSG=a: b'
+  - LIST:
+      - x
+stow:
+  - src: zsh
+    links:
+      - ${HOME}/.zshrc
+      # About zsh
+
+  # Config for bash
+  - bash:
+      - src: bashrc
+        links:
+          - ${HOME}/.bashrc
+  - src: alacritty
+    links:
+      - ${HOME}/.alacritty.yml
+";
+
+    #[test]
+    fn format_indents_by_two_spaces() {
+        assert_eq!(format(MESSY, false), FORMATTED);
+    }
+
+    #[test]
+    fn format_keeps_comments_with_their_lines() {
+        let formatted = format(MESSY, false);
+        // A comment above an item stays above it, and one indented under an item stays with it
+        assert!(formatted.contains("\n\n  # Config for bash\n  - bash:\n"));
+        assert!(formatted.contains("      - ${HOME}/.zshrc\n      # About zsh\n"));
+        // Runs of blank lines become one
+        assert!(!formatted.contains("\n\n\n"));
+    }
+
+    #[test]
+    fn format_keeps_detached_comments_detached() {
+        // A blank line after a comment keeps it from being attached to what follows
+        let text = "\
+# Header comment
+
+version: 1
+
+stow:
+    # Section header
+
+    - src: b
+      links:
+          - ${HOME}/b
+";
+        let expected = "\
+# Header comment
+
+version: 1
+
+stow:
+  # Section header
+
+  - src: b
+    links:
+      - ${HOME}/b
+";
+        assert_eq!(format(text, false), expected);
+        assert_eq!(format(expected, false), expected);
+        // Runs of blank lines between comments still become one
+        assert_eq!(
+            format("# a\n\n\n# b\n\n\nversion: 1\n", false),
+            "# a\n\n# b\n\nversion: 1\n"
+        );
+    }
+
+    #[test]
+    fn format_quotes_variables_only_when_needed() {
+        let formatted = format(MESSY, false);
+        assert!(formatted.contains("  - EDITOR=vim\n  - PAGER=less -R\n"));
+        // YAML would read an unquoted 'MSG=a: b' as a mapping
+        assert!(formatted.contains("  - 'MSG=a: b'\n"));
+        // The elements of list variables too, while numbers stay numbers
+        assert!(formatted.contains("  - LIST:\n      - x\n"));
+        let list = "vars:\n  - LIST:\n      - \"a\"\n      - '1'\n      - 2\n  - N: 3\n";
+        assert_eq!(
+            format(list, false),
+            "vars:\n  - LIST:\n      - a\n      - '1'\n      - 2\n  - N: 3\n"
+        );
+        // Only variables are unquoted, so quoted sources and links are left alone
+        assert_eq!(format(NESTED, false), NESTED);
+    }
+
+    #[test]
+    fn format_is_idempotent() {
+        for text in [MESSY, NESTED, VARS] {
+            for sort in [false, true] {
+                let formatted = format(text, sort);
+                assert_eq!(format(&formatted, sort), formatted);
+            }
+        }
+    }
+
+    #[test]
+    fn format_sorts_stow_lists() {
+        let sorted = format(MESSY, true);
+        // Comments move with their entries, and blank lines separate every entry if they
+        // separated any
+        let expected = "\
+# My dotfiles
+
+vars:
+  - EDITOR=vim
+  - PAGER=less -R
+  - 'MSG=a: b'
+  - LIST:
+      - x
+stow:
+  - src: alacritty
+    links:
+      - ${HOME}/.alacritty.yml
+
+  # Config for bash
+  - bash:
+      - src: bashrc
+        links:
+          - ${HOME}/.bashrc
+
+  - src: zsh
+    links:
+      - ${HOME}/.zshrc
+      # About zsh
+";
+        assert_eq!(sorted, expected);
+        // Without '--sort' the order is kept
+        assert_eq!(format(MESSY, false), FORMATTED);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::vars::xdg_home_dir;
+use age::armor::ArmoredReader;
+use age::{Decryptor, Identity, IdentityFile};
+use anyhow::{bail, Context, Result};
+use log::info;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{BufReader, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+/// Extension of age files, which is left off of the decrypted copy
+const AGE_EXTENSION: &str = "age";
+
+/// Where decrypted copies are kept, '$XDG_STATE_HOME/nstow/decrypted'
+fn decrypted_dir() -> Result<PathBuf> {
+    let Some(state_dir) = xdg_home_dir("XDG_STATE_HOME") else {
+        bail!("Unable to find a state directory for decrypted files");
+    };
+    Ok(state_dir.join("nstow").join("decrypted"))
+}
+
+/// Where the decrypted copy of an encrypted file is kept.
+/// The copy mirrors the encrypted file's absolute path so sources from different stow dirs never
+/// collide
+pub fn decrypted_path(src: &Path) -> Result<PathBuf> {
+    let src = src
+        .canonicalize()
+        .with_context(|| format!("Encrypted source file {:?} does not exist", src))?;
+    Ok(mirrored_path(&decrypted_dir()?, &src))
+}
+
+fn mirrored_path(dir: &Path, src: &Path) -> PathBuf {
+    let mut path = dir.to_path_buf();
+    path.extend(
+        src.components()
+            .filter(|component| matches!(component, Component::Normal(_))),
+    );
+    if path.extension().is_some_and(|ext| ext == AGE_EXTENSION) {
+        path.set_extension("");
+    }
+    path
+}
+
+/// Create a directory for decrypted copies, where it and every directory between it and the
+/// 'nstow' state directory are only accessible by the user
+fn create_private_dir(decrypted_dir: &Path, dir: &Path) -> Result<()> {
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    // The mode only applies to new directories
+    let nstow_dir = decrypted_dir.parent().unwrap_or(decrypted_dir);
+    for ancestor in dir
+        .ancestors()
+        .take_while(|ancestor| ancestor.starts_with(nstow_dir))
+    {
+        fs::set_permissions(ancestor, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+/// Decrypt an age file, encrypted to one of the identities in the identity file.
+/// The plaintext is only readable by the user
+pub fn decrypt(src: &Path, identity_file: &Path, dest: &Path) -> Result<()> {
+    let identities = IdentityFile::from_file(identity_file.display().to_string())
+        .with_context(|| format!("Unable to read age identity file {:?}", identity_file))?
+        .into_identities()
+        .with_context(|| format!("Invalid age identity file {:?}", identity_file))?;
+
+    let encrypted = File::open(src)?;
+    let decryptor = Decryptor::new(ArmoredReader::new(BufReader::new(encrypted)))
+        .with_context(|| format!("{:?} is not an age encrypted file", src))?;
+    let mut reader = decryptor
+        .decrypt(
+            identities
+                .iter()
+                .map(|identity| identity.as_ref() as &dyn Identity),
+        )
+        .with_context(|| format!("Unable to decrypt {:?} with {:?}", src, identity_file))?;
+    let mut plaintext = Vec::new();
+    reader.read_to_end(&mut plaintext)?;
+
+    if let Some(parent) = dest.parent() {
+        create_private_dir(&decrypted_dir()?, parent)?;
+    }
+    info!("Decrypting {:?} to {:?}", src, dest);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(dest)?;
+    // The mode only applies to new files
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(&plaintext)?;
+    Ok(())
+}
+
+/// The decrypted copy is stale when the encrypted file changed after it was decrypted
+pub fn is_stale(src: &Path, decrypted: &Path) -> Result<bool> {
+    let encrypted_modified = fs::metadata(src)?.modified()?;
+    match fs::metadata(decrypted) {
+        Ok(metadata) => Ok(metadata.modified()? < encrypted_modified),
+        Err(_) => Ok(true),
+    }
+}
+
+/// Remove a decrypted copy, along with the directories it leaves empty
+pub fn remove_decrypted(decrypted: &Path) -> Result<()> {
+    remove_from(&decrypted_dir()?, decrypted)
+}
+
+fn remove_from(decrypted_dir: &Path, decrypted: &Path) -> Result<()> {
+    fs::remove_file(decrypted)?;
+    for dir in decrypted
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| dir.starts_with(decrypted_dir) && *dir != decrypted_dir)
+    {
+        // Stop at the first directory still holding other copies
+        if fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch_dir;
+    use std::time::{Duration, SystemTime};
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn decrypted_path_mirrors_the_source_without_the_age_extension() {
+        let dir = Path::new("/state/nstow/decrypted");
+        assert_eq!(
+            mirrored_path(dir, Path::new("/home/me/dotfiles/ssh/config.age")),
+            PathBuf::from("/state/nstow/decrypted/home/me/dotfiles/ssh/config")
+        );
+        assert_eq!(
+            mirrored_path(dir, Path::new("/home/me/dotfiles/backup.tar.age")),
+            PathBuf::from("/state/nstow/decrypted/home/me/dotfiles/backup.tar")
+        );
+        assert_eq!(
+            mirrored_path(dir, Path::new("/home/me/dotfiles/notes.txt")),
+            PathBuf::from("/state/nstow/decrypted/home/me/dotfiles/notes.txt")
+        );
+    }
+
+    #[test]
+    fn decrypted_path_needs_an_existing_source() {
+        let dir = scratch_dir("decrypted-path-missing");
+        let error = decrypted_path(&dir.join("missing.age")).unwrap_err();
+        assert!(error.to_string().contains("does not exist"));
+    }
+
+    #[test]
+    fn stale_when_the_copy_is_missing_or_older_than_the_source() {
+        let dir = scratch_dir("stale");
+        let src = dir.join("config.age");
+        let decrypted = dir.join("config");
+        fs::write(&src, "encrypted").unwrap();
+        assert!(is_stale(&src, &decrypted).unwrap());
+
+        let now = SystemTime::now();
+        File::create(&src).unwrap().set_modified(now).unwrap();
+        fs::write(&decrypted, "plaintext").unwrap();
+        File::options()
+            .write(true)
+            .open(&decrypted)
+            .unwrap()
+            .set_modified(now + Duration::from_secs(1))
+            .unwrap();
+        assert!(!is_stale(&src, &decrypted).unwrap());
+
+        File::options()
+            .write(true)
+            .open(&src)
+            .unwrap()
+            .set_modified(now + Duration::from_secs(2))
+            .unwrap();
+        assert!(is_stale(&src, &decrypted).unwrap());
+    }
+
+    #[test]
+    fn private_dir_is_private_up_to_the_nstow_dir() {
+        let state = scratch_dir("private-dir");
+        let nstow = state.join("nstow");
+        let decrypted_dir = nstow.join("decrypted");
+        fs::create_dir_all(&decrypted_dir).unwrap();
+        fs::set_permissions(&nstow, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::set_permissions(&decrypted_dir, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let dir = decrypted_dir.join("home/me/dotfiles");
+        create_private_dir(&decrypted_dir, &dir).unwrap();
+        for path in [&nstow, &decrypted_dir, &decrypted_dir.join("home"), &dir] {
+            assert_eq!(mode(path), 0o700, "{:?}", path);
+        }
+        // Directories above nstow's own are left alone
+        assert_ne!(mode(&state), 0o700);
+    }
+
+    #[test]
+    fn removing_a_copy_removes_the_directories_it_leaves_empty() {
+        let decrypted_dir = scratch_dir("remove").join("decrypted");
+        let dotfiles = decrypted_dir.join("home/me/dotfiles");
+        fs::create_dir_all(dotfiles.join("ssh")).unwrap();
+        fs::write(dotfiles.join("ssh/config"), "").unwrap();
+        fs::write(dotfiles.join("netrc"), "").unwrap();
+
+        remove_from(&decrypted_dir, &dotfiles.join("ssh/config")).unwrap();
+        assert!(!dotfiles.join("ssh").exists());
+        assert!(dotfiles.join("netrc").exists());
+
+        remove_from(&decrypted_dir, &dotfiles.join("netrc")).unwrap();
+        assert!(!decrypted_dir.join("home").exists());
+        assert!(decrypted_dir.exists());
+    }
+}
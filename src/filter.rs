@@ -1,6 +1,4 @@
-use anyhow::{bail, Result};
-use lazy_static::lazy_static;
-use log::{debug, error, info, trace, warn};
+use log::info;
 use regex::Regex;
 
 #[derive(Debug)]
@@ -30,29 +28,20 @@ pub struct FilterCombo {
 }
 impl FilterCombo {
     pub fn new(only_strings: Option<Vec<String>>, ignore_strings: Option<Vec<String>>) -> Self {
-        let only = if let Some(only_strings) = only_strings {
-            Some(Filter::new(only_strings))
-        } else {
-            None
-        };
-
-        let ignore = if let Some(ignore_strings) = ignore_strings {
-            Some(Filter::new(ignore_strings))
-        } else {
-            None
-        };
+        let only = only_strings.map(Filter::new);
+        let ignore = ignore_strings.map(Filter::new);
         FilterCombo { only, ignore }
     }
 
     fn check_against_filters(&self, input: &str) -> bool {
         if let Some(only) = &self.only {
-            if !only.matches(&input) {
+            if !only.matches(input) {
                 info!("Input {} did not match any 'only' regexes. Ignoring", input);
                 return false;
             }
         }
         if let Some(ignore) = &self.ignore {
-            if ignore.matches(&input) {
+            if ignore.matches(input) {
                 info!("Input {} matched an 'ignore' regex. Ignoring", input);
                 return false;
             }
@@ -61,10 +50,38 @@ impl FilterCombo {
     }
 }
 
+/// Selects stowfile entries by their tags
+#[derive(Debug)]
+pub struct TagFilter {
+    only: Vec<String>,
+    skip: Vec<String>,
+}
+impl TagFilter {
+    pub fn new(only: Vec<String>, skip: Vec<String>) -> Self {
+        TagFilter { only, skip }
+    }
+
+    /// Returns why an entry with these tags is excluded, or None if it should be stowed
+    fn exclusion_reason(&self, tags: &[String]) -> Option<String> {
+        if let Some(skipped) = tags.iter().find(|tag| self.skip.contains(tag)) {
+            return Some(format!("tag '{}' is skipped with '--skip-tag'", skipped));
+        }
+        if !self.only.is_empty() && !tags.iter().any(|tag| self.only.contains(tag)) {
+            return Some(format!(
+                "no tags match '--tag {}'",
+                self.only.join("', '--tag ")
+            ));
+        }
+        None
+    }
+}
+
 #[derive(Debug)]
 pub struct StowFilters {
     src_filter: Option<FilterCombo>,
     target_filter: Option<FilterCombo>,
+    tag_filter: TagFilter,
+    #[allow(dead_code)] // TODO: override existing targets when linking
     override_filter: Option<Filter>,
 }
 impl StowFilters {
@@ -74,6 +91,7 @@ impl StowFilters {
         only_target: Option<Vec<String>>,
         ignore_target: Option<Vec<String>>,
         overrides: Option<Vec<String>>,
+        tag_filter: TagFilter,
     ) -> Self {
         let src_filter = if only.is_some() || ignore.is_some() {
             Some(FilterCombo::new(only, ignore))
@@ -86,15 +104,12 @@ impl StowFilters {
             None
         };
 
-        let override_filter = if let Some(overrides) = overrides {
-            Some(Filter::new(overrides))
-        } else {
-            None
-        };
+        let override_filter = overrides.map(Filter::new);
 
         StowFilters {
             src_filter,
             target_filter,
+            tag_filter,
             override_filter,
         }
     }
@@ -116,6 +131,11 @@ impl StowFilters {
         }
     }
 
+    pub fn tag_exclusion_reason(&self, tags: &[String]) -> Option<String> {
+        self.tag_filter.exclusion_reason(tags)
+    }
+
+    #[allow(dead_code)]
     pub fn check_target_override(&self, input: &str) -> bool {
         if let Some(override_filter) = &self.override_filter {
             override_filter.matches(input)
@@ -125,3 +145,59 @@ impl StowFilters {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(only: &[&str], skip: &[&str]) -> TagFilter {
+        let strings = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect();
+        TagFilter::new(strings(only), strings(skip))
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn everything_is_stowed_without_tag_flags() {
+        let filter = filter(&[], &[]);
+        assert_eq!(filter.exclusion_reason(&tags(&[])), None);
+        assert_eq!(filter.exclusion_reason(&tags(&["gui"])), None);
+    }
+
+    #[test]
+    fn tag_keeps_only_entries_with_a_matching_tag() {
+        let filter = filter(&["gui", "work"], &[]);
+        assert_eq!(filter.exclusion_reason(&tags(&["gui"])), None);
+        assert_eq!(filter.exclusion_reason(&tags(&["cli", "work"])), None);
+        assert_eq!(
+            filter.exclusion_reason(&tags(&["cli"])),
+            Some("no tags match '--tag gui', '--tag work'".to_string())
+        );
+        // Untagged entries have nothing to match
+        assert!(filter.exclusion_reason(&tags(&[])).is_some());
+    }
+
+    #[test]
+    fn skip_tag_drops_entries_with_any_skipped_tag() {
+        let filter = filter(&[], &["work"]);
+        assert_eq!(filter.exclusion_reason(&tags(&[])), None);
+        assert_eq!(filter.exclusion_reason(&tags(&["gui"])), None);
+        assert_eq!(
+            filter.exclusion_reason(&tags(&["gui", "work"])),
+            Some("tag 'work' is skipped with '--skip-tag'".to_string())
+        );
+    }
+
+    #[test]
+    fn skip_tag_wins_over_tag() {
+        let filter = filter(&["gui"], &["work"]);
+        assert_eq!(filter.exclusion_reason(&tags(&["gui"])), None);
+        assert_eq!(
+            filter.exclusion_reason(&tags(&["gui", "work"])),
+            Some("tag 'work' is skipped with '--skip-tag'".to_string())
+        );
+        assert!(filter.exclusion_reason(&tags(&["cli"])).is_some());
+    }
+}
@@ -0,0 +1,148 @@
+use crate::settings::LinkSettings;
+use anyhow::{bail, Result};
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// When a hook runs, relative to the links it covers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookKind {
+    PreStow,
+    PostStow,
+    PreUnstow,
+    PostUnstow,
+}
+impl HookKind {
+    const ALL: &'static [HookKind] = &[
+        HookKind::PreStow,
+        HookKind::PostStow,
+        HookKind::PreUnstow,
+        HookKind::PostUnstow,
+    ];
+
+    fn key(&self) -> &'static str {
+        match self {
+            HookKind::PreStow => "pre_stow",
+            HookKind::PostStow => "post_stow",
+            HookKind::PreUnstow => "pre_unstow",
+            HookKind::PostUnstow => "post_unstow",
+        }
+    }
+}
+
+/// Commands from a 'hooks' section, which run when any of the links under the node that declared
+/// them are stowed or unstowed
+#[derive(Debug)]
+pub struct Hooks<'a> {
+    // The stowfile or node that declared the hooks, for log messages
+    node: String,
+    commands: Vec<(HookKind, String)>,
+    // Resolved variables in the node's scope
+    env: Vec<(String, String)>,
+    dir: PathBuf,
+    settings: &'a LinkSettings,
+}
+impl<'a> Hooks<'a> {
+    /// Each hook is a command or a list of commands
+    pub fn from_yaml(
+        hooks: &serde_yaml::Value,
+        node: &str,
+        env: Vec<(String, String)>,
+        dir: &Path,
+        settings: &'a LinkSettings,
+    ) -> Result<Self> {
+        let Some(hooks) = hooks.as_mapping() else {
+            bail!(
+                "Malformatted stowfile. 'hooks' of '{}' must be a mapping",
+                node
+            );
+        };
+        let mut commands = Vec::new();
+        for (key, value) in hooks {
+            let Some(kind) = HookKind::ALL
+                .iter()
+                .find(|kind| key.as_str() == Some(kind.key()))
+            else {
+                bail!(
+                    "Malformatted stowfile. Unknown hook {:?} for '{}'. Expected one of 'pre_stow', 'post_stow', 'pre_unstow', or 'post_unstow'",
+                    key,
+                    node
+                );
+            };
+            match value {
+                serde_yaml::Value::String(command) => commands.push((*kind, command.clone())),
+                serde_yaml::Value::Sequence(list) => {
+                    for command in list {
+                        let Some(command) = command.as_str() else {
+                            bail!(
+                                "Malformatted stowfile. {:?} in hook '{}' of '{}' is not a string",
+                                command,
+                                kind.key(),
+                                node
+                            );
+                        };
+                        commands.push((*kind, command.to_string()));
+                    }
+                }
+                _ => bail!(
+                    "Malformatted stowfile. Hook '{}' of '{}' must be a command or a list of commands",
+                    kind.key(),
+                    node
+                ),
+            }
+        }
+        Ok(Hooks {
+            node: node.to_string(),
+            commands,
+            env,
+            dir: dir.to_path_buf(),
+            settings,
+        })
+    }
+
+    /// Run each command for a kind of hook, stopping at the first failure
+    pub fn run(&self, kind: HookKind) -> Result<()> {
+        for (_, command) in self.commands.iter().filter(|(k, _)| *k == kind) {
+            if !self.settings.allow_hooks() {
+                warn!(
+                    "Skipping {} hook for '{}', since hooks are not allowed. Pass '--allow-hooks' to run it: {}",
+                    kind.key(),
+                    self.node,
+                    command
+                );
+                continue;
+            }
+            if self.settings.dry_run() {
+                info!(
+                    "Pretending to run {} hook for '{}': {}",
+                    kind.key(),
+                    self.node,
+                    command
+                );
+                continue;
+            }
+            info!(
+                "Running {} hook for '{}': {}",
+                kind.key(),
+                self.node,
+                command
+            );
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .current_dir(&self.dir)
+                .envs(self.env.iter().cloned())
+                .status()?;
+            if !status.success() {
+                bail!(
+                    "The {} hook for '{}' failed ({}): {}",
+                    kind.key(),
+                    self.node,
+                    status,
+                    command
+                );
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+use crate::edit::change_stowfile;
+use crate::init::{format_stow_section, Entry};
+use crate::settings::Settings;
+use crate::vars::{escape_variables, path_text};
+use anyhow::{Context, Result};
+use log::{debug, info};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// A symlink that resolves into the stow dir
+#[derive(Debug)]
+struct FoundLink {
+    // Relative to the stow dir
+    src: PathBuf,
+    link: PathBuf,
+}
+
+/// Look for symlinks resolving into the stow dir, without following symlinked directories or
+/// looking inside the stow dir itself. Unreadable directories are skipped
+fn walk(dir: &Path, stow_dir: &Path, found: &mut Vec<FoundLink>) {
+    let children = match fs::read_dir(dir) {
+        Ok(children) => children,
+        Err(e) => {
+            debug!("Skipping {:?}: {}", dir, e);
+            return;
+        }
+    };
+    for child in children.flatten() {
+        let path = child.path();
+        let Ok(file_type) = child.file_type() else {
+            continue;
+        };
+        if file_type.is_symlink() {
+            // Broken symlinks can't point into the stow dir
+            let Ok(points_to) = path.canonicalize() else {
+                continue;
+            };
+            // A link to the stow dir itself has no source to stow
+            if let Some(src) = points_to
+                .strip_prefix(stow_dir)
+                .ok()
+                .filter(|src| !src.as_os_str().is_empty())
+            {
+                debug!("Found {:?} -> {:?}", path, points_to);
+                found.push(FoundLink {
+                    src: src.to_path_buf(),
+                    link: path,
+                });
+            }
+        } else if file_type.is_dir() {
+            if path
+                .canonicalize()
+                .is_ok_and(|dir| dir.starts_with(stow_dir))
+            {
+                continue;
+            }
+            walk(&path, stow_dir, found);
+        }
+    }
+}
+
+/// Symlinks under the roots that point into the stow dir, sorted by their source
+fn find_links(roots: &[PathBuf], stow_dir: &Path) -> Result<Vec<FoundLink>> {
+    let stow_dir = stow_dir
+        .canonicalize()
+        .with_context(|| format!("Unable to resolve stow dir {:?}", stow_dir))?;
+    let mut found = Vec::new();
+    for root in roots {
+        let root = std::path::absolute(root)?;
+        info!("Looking for symlinks into {:?} under {:?}", stow_dir, root);
+        walk(&root, &stow_dir, &mut found);
+    }
+    found.sort_by(|a, b| (&a.src, &a.link).cmp(&(&b.src, &b.link)));
+    info!("Found {} symlinks", found.len());
+    Ok(found)
+}
+
+/// Stowfile entries that reproduce the symlinks under the roots that point into the stow dir
+pub fn import_links(roots: &[PathBuf], stow_dir: &Path) -> Result<Option<String>> {
+    let found = find_links(roots, stow_dir)?;
+    if found.is_empty() {
+        return Ok(None);
+    }
+
+    let mut entries = Vec::new();
+    for FoundLink { src, link } in &found {
+        let path: Vec<&str> = src
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => name.to_str(),
+                _ => None,
+            })
+            .collect();
+        Entry::insert(&mut entries, &path, path_text(link));
+    }
+    Ok(Some(format_stow_section(&entries)?))
+}
+
+/// Add entries for the symlinks to the stowfile. Sources that already have an entry are left
+/// alone
+pub fn write_links(settings: &Settings, roots: &[PathBuf]) -> Result<()> {
+    let found = find_links(roots, settings.current_working_dir())?;
+    let mut sources: Vec<(String, Vec<String>)> = Vec::new();
+    for FoundLink { src, link } in &found {
+        let src = escape_variables(&src.display().to_string());
+        match sources.last_mut() {
+            Some((last, links)) if *last == src => links.push(path_text(link)),
+            _ => sources.push((src, vec![path_text(link)])),
+        }
+    }
+
+    let mut added = 0;
+    change_stowfile(settings, "add the symlinks", |stowfile| {
+        for (src, links) in &sources {
+            if stowfile.has_entry(src) {
+                info!("'{}' is already in the stowfile", src);
+                continue;
+            }
+            stowfile.add_entry(src, links)?;
+            added += 1;
+        }
+        Ok(())
+    })?;
+    info!("Added {} entries", added);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::StowfileSettings;
+    use crate::testing::{scratch_dir, settings};
+    use std::os::unix::fs::symlink;
+
+    /// A stow dir of dotfiles, and a home dir with symlinks into it and elsewhere
+    fn linked_home(name: &str) -> (PathBuf, PathBuf) {
+        let root = scratch_dir(name);
+        let stow_dir = root.join("dotfiles");
+        let home = root.join("home");
+        fs::create_dir_all(stow_dir.join("nvim")).unwrap();
+        fs::create_dir_all(home.join(".config/nvim")).unwrap();
+        for file in ["bashrc", "$weird", "nvim/init.lua", "stowfile"] {
+            fs::write(stow_dir.join(file), "").unwrap();
+        }
+        fs::write(root.join("elsewhere"), "").unwrap();
+        let links = [
+            ("dotfiles/bashrc", "home/.bashrc"),
+            ("dotfiles/bashrc", "home/.profile"),
+            ("dotfiles/$weird", "home/$weird"),
+            ("dotfiles/nvim/init.lua", "home/.config/nvim/init.lua"),
+            // The stow dir itself has no source to stow
+            ("dotfiles", "home/dotfiles"),
+            // Symlinked dirs aren't followed, or init.lua would be found twice
+            ("home/.config", "home/config"),
+            ("elsewhere", "home/elsewhere"),
+            ("missing", "home/broken"),
+            // Links inside the stow dir aren't looked at
+            ("dotfiles/bashrc", "dotfiles/bashrc-link"),
+        ];
+        for (src, link) in links {
+            symlink(root.join(src), root.join(link)).unwrap();
+        }
+        (root, stow_dir)
+    }
+
+    #[test]
+    fn import_finds_symlinks_into_the_stow_dir() {
+        let (root, stow_dir) = linked_home("import");
+        let text = import_links(std::slice::from_ref(&root), &stow_dir)
+            .unwrap()
+            .unwrap();
+        let expected = r#"stow:
+  - src: $$weird
+    links:
+      - "ROOT/home/$$weird"
+  - src: bashrc
+    links:
+      - "ROOT/home/.bashrc"
+      - "ROOT/home/.profile"
+  - nvim:
+      - src: init.lua
+        links:
+          - "ROOT/home/.config/nvim/init.lua"
+"#;
+        assert_eq!(text, expected.replace("ROOT", &root.display().to_string()));
+    }
+
+    #[test]
+    fn import_without_symlinks_finds_nothing() {
+        let root = scratch_dir("import-nothing");
+        fs::create_dir_all(root.join("dotfiles")).unwrap();
+        fs::create_dir_all(root.join("home")).unwrap();
+        let found = import_links(&[root.join("home")], &root.join("dotfiles")).unwrap();
+        assert!(found.is_none());
+    }
+
+    #[test]
+    fn write_links_leaves_sources_with_an_entry_alone() {
+        let (root, stow_dir) = linked_home("import-write");
+        let stowfile = stow_dir.join("stowfile");
+        fs::write(
+            &stowfile,
+            "version: 1
+
+stow:
+  - src: bashrc
+    links:
+      - ${HOME}/.bashrc
+",
+        )
+        .unwrap();
+        let settings = settings(
+            vec![StowfileSettings::new(stowfile.clone(), stow_dir.clone())],
+            stow_dir,
+            false,
+        );
+        write_links(&settings, &[root.join("home")]).unwrap();
+        let expected = r#"version: 1
+
+stow:
+  - src: bashrc
+    links:
+      - ${HOME}/.bashrc
+  - src: $$weird
+    links:
+      - "ROOT/home/$$weird"
+  - src: nvim/init.lua
+    links:
+      - "ROOT/home/.config/nvim/init.lua"
+"#
+        .replace("ROOT", &root.display().to_string());
+        assert_eq!(fs::read_to_string(&stowfile).unwrap(), expected);
+
+        // Importing again finds entries for every source
+        write_links(&settings, &[root.join("home")]).unwrap();
+        assert_eq!(fs::read_to_string(&stowfile).unwrap(), expected);
+    }
+}
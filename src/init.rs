@@ -0,0 +1,214 @@
+use crate::vars::escape_variables;
+use crate::version::STOWFILE_VERSION;
+use anyhow::{bail, Result};
+use log::debug;
+use std::fs;
+use std::path::Path;
+
+/// Like GNU Stow's '--dotfiles', files named 'dot-NAME' are linked to '.NAME'
+const DOT_PREFIX: &str = "dot-";
+
+/// A source file, or a directory of them, to write into a stowfile
+#[derive(Debug)]
+pub enum Entry {
+    File { name: String, links: Vec<String> },
+    Dir { name: String, contents: Vec<Entry> },
+}
+impl Entry {
+    /// Add a link to the source at a relative path, creating directory entries on the way
+    pub fn insert(entries: &mut Vec<Entry>, path: &[&str], link: String) {
+        let Some((name, rest)) = path.split_first() else {
+            return;
+        };
+        if rest.is_empty() {
+            let existing = entries.iter_mut().find_map(|entry| match entry {
+                Entry::File { name: n, links } if n == name => Some(links),
+                _ => None,
+            });
+            match existing {
+                Some(links) => links.push(link),
+                None => entries.push(Entry::File {
+                    name: name.to_string(),
+                    links: vec![link],
+                }),
+            }
+            return;
+        }
+
+        let existing = entries
+            .iter()
+            .position(|entry| matches!(entry, Entry::Dir { name: n, .. } if n == name));
+        let index = match existing {
+            Some(index) => index,
+            None => {
+                entries.push(Entry::Dir {
+                    name: name.to_string(),
+                    contents: Vec::new(),
+                });
+                entries.len() - 1
+            }
+        };
+        if let Entry::Dir { contents, .. } = &mut entries[index] {
+            Entry::insert(contents, rest, link);
+        }
+    }
+}
+
+fn target_name(name: &str) -> String {
+    match name.strip_prefix(DOT_PREFIX) {
+        Some(rest) => format!(".{}", rest),
+        None => name.to_string(),
+    }
+}
+
+/// The reverse of `target_name`, so hidden files aren't hidden in the stow dir
+pub fn source_name(name: &str) -> String {
+    match name.strip_prefix('.') {
+        Some(rest) if !rest.is_empty() => format!("{}{}", DOT_PREFIX, rest),
+        _ => name.to_string(),
+    }
+}
+
+fn yaml_quoted(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Quote a name only when YAML needs it
+fn yaml_scalar(text: &str) -> Result<String> {
+    let value = serde_yaml::Value::String(escape_variables(text));
+    Ok(serde_yaml::to_string(&value)?.trim_end().to_string())
+}
+
+/// Hidden files (like '.git') and symlinks are left out, along with anything in `skip` at the top
+/// level of the directory. Each file is linked to the same path in the target dir
+fn scan(dir: &Path, skip: &[&str], target_dir: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut children = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    children.sort_by_key(|child| child.file_name());
+    for child in children {
+        let Some(name) = child.file_name().to_str().map(str::to_string) else {
+            bail!("{:?} is not a valid UTF-8 file name", child.path());
+        };
+        let file_type = child.file_type()?;
+        if name.starts_with('.') || skip.contains(&name.as_str()) || file_type.is_symlink() {
+            debug!("Leaving {:?} out of the stowfile", child.path());
+            continue;
+        }
+        let target = format!("{}/{}", target_dir, escape_variables(&target_name(&name)));
+        if file_type.is_dir() {
+            let contents = scan(&child.path(), &[], &target)?;
+            if !contents.is_empty() {
+                entries.push(Entry::Dir { name, contents });
+            }
+        } else {
+            entries.push(Entry::File {
+                name,
+                links: vec![target],
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Write entries in the nested mapping layout the stowfile is read with. Each directory is a
+/// mapping of its name to a list of its contents
+fn write_entries(out: &mut String, entries: &[Entry], indent: usize) -> Result<()> {
+    let pad = " ".repeat(indent);
+    for entry in entries {
+        match entry {
+            Entry::File { name, links } => {
+                out.push_str(&format!("{}- src: {}\n", pad, yaml_scalar(name)?));
+                out.push_str(&format!("{}  links:\n", pad));
+                for link in links {
+                    out.push_str(&format!("{}    - {}\n", pad, yaml_quoted(link)));
+                }
+            }
+            Entry::Dir { name, contents } => {
+                out.push_str(&format!("{}- {}:\n", pad, yaml_scalar(name)?));
+                write_entries(out, contents, indent + 4)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The 'stow' section of a stowfile, as text
+pub fn format_stow_section(entries: &[Entry]) -> Result<String> {
+    let mut out = String::from("stow:\n");
+    write_entries(&mut out, entries, 2)?;
+    Ok(out)
+}
+
+/// Propose a stowfile linking every file in the directory into the target dir
+pub fn generate_stowfile(dir: &Path, target_dir: &str, skip: &[&str]) -> Result<String> {
+    let entries = scan(dir, skip, target_dir.trim_end_matches('/'))?;
+    if entries.is_empty() {
+        bail!("No files to stow in {:?}", dir);
+    }
+    Ok(format!(
+        "---\nversion: {}\n\n{}",
+        STOWFILE_VERSION,
+        format_stow_section(&entries)?
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch_dir;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn dot_names_swap_between_source_and_target() {
+        assert_eq!(target_name("dot-bashrc"), ".bashrc");
+        assert_eq!(target_name("bashrc"), "bashrc");
+        assert_eq!(source_name(".bashrc"), "dot-bashrc");
+        assert_eq!(source_name("bashrc"), "bashrc");
+        assert_eq!(source_name("."), ".");
+    }
+
+    #[test]
+    fn generated_stowfile_links_every_file_in_the_dir() {
+        let dir = scratch_dir("init");
+        fs::write(dir.join("stowfile"), "").unwrap();
+        fs::write(dir.join("dot-bashrc"), "").unwrap();
+        fs::write(dir.join("$weird"), "").unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::write(dir.join(".git/config"), "").unwrap();
+        fs::create_dir_all(dir.join("dot-config/nvim")).unwrap();
+        fs::write(dir.join("dot-config/nvim/init.lua"), "").unwrap();
+        fs::create_dir_all(dir.join("empty")).unwrap();
+        symlink(dir.join("dot-config"), dir.join("linked")).unwrap();
+        symlink(dir.join("dot-bashrc"), dir.join("linked-file")).unwrap();
+
+        let stowfile = generate_stowfile(&dir, "${HOME}/", &["stowfile"]).unwrap();
+        // Hidden files, empty dirs, symlinks, and the stowfile are left out
+        assert_eq!(
+            stowfile,
+            r#"---
+version: 1
+
+stow:
+  - src: $$weird
+    links:
+      - "${HOME}/$$weird"
+  - src: dot-bashrc
+    links:
+      - "${HOME}/.bashrc"
+  - dot-config:
+      - nvim:
+          - src: init.lua
+            links:
+              - "${HOME}/.config/nvim/init.lua"
+"#
+        );
+    }
+
+    #[test]
+    fn nothing_to_stow_is_an_error() {
+        let dir = scratch_dir("init-empty");
+        fs::write(dir.join("stowfile"), "").unwrap();
+        fs::write(dir.join(".hidden"), "").unwrap();
+        assert!(generate_stowfile(&dir, "${HOME}", &["stowfile"]).is_err());
+    }
+}
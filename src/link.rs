@@ -1,8 +1,10 @@
+use crate::encrypted;
+use crate::location::Location;
 use crate::settings::LinkSettings;
 use anyhow::{bail, Result};
-use log::{debug, error, info, trace, warn, Level};
+use log::{info, warn};
 use std::{
-    fs::{self, Metadata},
+    fmt, fs,
     path::{Path, PathBuf},
 };
 
@@ -12,7 +14,7 @@ enum FileType {
     File,
     Symlink(PathBuf), // Includes the path the link points to
     BrokenSymlink,
-    Other(Metadata),
+    Other,
 }
 
 #[derive(Debug)]
@@ -46,22 +48,21 @@ impl Target {
                 if exists {
                     if path.is_symlink() {
                         let points_to = path.canonicalize().unwrap();
-                        return Ok(Some(FileType::Symlink(points_to)));
+                        Ok(Some(FileType::Symlink(points_to)))
                     } else if path.is_file() {
-                        return Ok(Some(FileType::File));
+                        Ok(Some(FileType::File))
                     } else if path.is_dir() {
-                        return Ok(Some(FileType::Dir));
+                        Ok(Some(FileType::Dir))
                     } else {
-                        let metadata = path.metadata()?;
-                        return Ok(Some(FileType::Other(metadata)));
+                        Ok(Some(FileType::Other))
                     }
                 } else {
                     // Check for broken symlink. I think try_exists() returns Ok(false) with broken
                     // symlinks
                     if path.is_symlink() {
-                        return Ok(Some(FileType::BrokenSymlink));
+                        Ok(Some(FileType::BrokenSymlink))
                     } else {
-                        return Ok(None);
+                        Ok(None)
                     }
                 }
             }
@@ -76,14 +77,75 @@ fn files_are_the_same(a: &Path, b: &Path) -> Result<bool> {
     Ok(a.canonicalize()? == b.canonicalize()?)
 }
 
+/// What is at a link's target, as far as stowing and unstowing are concerned
+#[derive(Debug, Clone, PartialEq)]
+pub enum TargetState {
+    Missing,
+    LinkedTo(PathBuf),
+    BrokenSymlink,
+    Occupied(&'static str),
+}
+impl fmt::Display for TargetState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TargetState::Missing => write!(f, "missing"),
+            TargetState::LinkedTo(points_to) => write!(f, "a symlink to {:?}", points_to),
+            TargetState::BrokenSymlink => write!(f, "a broken symlink"),
+            TargetState::Occupied(kind) => write!(f, "an existing {}", kind),
+        }
+    }
+}
+
+/// An encrypted source is decrypted to a private copy, which is what the target links to
+#[derive(Debug)]
+pub struct Encryption {
+    decrypted: PathBuf,
+    identity: Option<PathBuf>,
+}
+impl Encryption {
+    pub fn new(src: &Path, identity: Option<PathBuf>) -> Result<Self> {
+        Ok(Encryption {
+            decrypted: encrypted::decrypted_path(src)?,
+            identity,
+        })
+    }
+
+    /// An encrypted source whose decrypted copy is at a given path
+    #[cfg(test)]
+    pub fn at(decrypted: PathBuf, identity: Option<PathBuf>) -> Self {
+        Encryption {
+            decrypted,
+            identity,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Link<'a> {
     src: PathBuf,
     target: Target,
+    tags: Vec<String>,
+    package: Option<String>,
+    // Indices of the hooks of the nodes the link is in
+    hooks: Vec<usize>,
+    encryption: Option<Encryption>,
+    // The layer the link is from, and the earlier layers whose links to the same target it replaced
+    layer: Option<String>,
+    replaced_layers: Vec<String>,
+    // Where the target is written in the stowfile
+    location: Option<Location>,
     settings: &'a LinkSettings,
 }
 impl<'a> Link<'a> {
-    pub fn new(src: String, target: String, settings: &'a LinkSettings) -> Result<Self> {
+    pub fn new(
+        src: String,
+        target: String,
+        tags: Vec<String>,
+        package: Option<String>,
+        hooks: Vec<usize>,
+        encryption: Option<Encryption>,
+        settings: &'a LinkSettings,
+    ) -> Result<Self> {
         let src = PathBuf::from(src);
         if src.canonicalize().is_err() {
             bail!("Source file {:?} does not exist", src);
@@ -92,108 +154,212 @@ impl<'a> Link<'a> {
         Ok(Link {
             src,
             target,
+            tags,
+            package,
+            hooks,
+            encryption,
+            layer: None,
+            replaced_layers: Vec::new(),
+            location: None,
             settings,
         })
     }
 
+    pub fn src(&self) -> &Path {
+        &self.src
+    }
+
+    pub fn target(&self) -> &Path {
+        &self.target.path
+    }
+
+    /// The file the target links to, which is the decrypted copy of an encrypted source
+    pub fn destination(&self) -> &Path {
+        match &self.encryption {
+            Some(encryption) => &encryption.decrypted,
+            None => &self.src,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    /// Check if a symlink pointing to this path would be this link
+    pub fn is_source(&self, points_to: &Path) -> bool {
+        points_to == self.destination()
+            || files_are_the_same(self.destination(), points_to).unwrap_or(false)
+    }
+
+    /// Why an encrypted source cannot be decrypted, if it can't
+    pub fn decryption_problem(&self) -> Option<String> {
+        match &self.encryption {
+            Some(Encryption { identity: None, .. }) => Some(format!(
+                "Source file {:?} is encrypted, but no age identity was given. Pass '--identity' or set 'age_identity' in the stowfile",
+                self.src
+            )),
+            _ => None,
+        }
+    }
+
+    /// Check if the decrypted copy of an encrypted source is older than the source
+    pub fn is_stale(&self) -> Result<bool> {
+        match &self.encryption {
+            Some(encryption) => encrypted::is_stale(&self.src, &encryption.decrypted),
+            None => Ok(false),
+        }
+    }
+
+    /// Write the decrypted copy of an encrypted source
+    pub fn decrypt(&self) -> Result<()> {
+        let Some(encryption) = &self.encryption else {
+            return Ok(());
+        };
+        let Some(identity) = &encryption.identity else {
+            bail!(self.decryption_problem().unwrap_or_default());
+        };
+        if self.settings.dry_run() {
+            info!(
+                "Pretending to decrypt {:?} to {:?}",
+                self.src, encryption.decrypted
+            );
+            Ok(())
+        } else {
+            encrypted::decrypt(&self.src, identity, &encryption.decrypted)
+        }
+    }
+
+    pub fn target_state(&self) -> Result<TargetState> {
+        let state = match self.target.file_type()? {
+            None => TargetState::Missing,
+            Some(FileType::Symlink(points_to)) => TargetState::LinkedTo(points_to),
+            Some(FileType::BrokenSymlink) => TargetState::BrokenSymlink,
+            Some(FileType::Dir) => TargetState::Occupied("directory"),
+            Some(FileType::File) => TargetState::Occupied("file"),
+            Some(FileType::Other) => TargetState::Occupied("file of an un-handable type"),
+        };
+        Ok(state)
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub fn package(&self) -> Option<&str> {
+        self.package.as_deref()
+    }
+
+    /// Shift the link's hook indices past the hooks of stowfiles loaded before its own
+    pub fn offset_hooks(&mut self, offset: usize) {
+        for index in &mut self.hooks {
+            *index += offset;
+        }
+    }
+
+    pub fn set_location(&mut self, location: Location) {
+        self.location = Some(location);
+    }
+
+    /// Where the link is defined, along with its source, for error messages
+    pub fn describe_origin(&self) -> String {
+        match &self.location {
+            Some(location) => format!("{} ({:?})", location, self.src),
+            None => format!("{:?}", self.src),
+        }
+    }
+
+    pub fn set_layer(&mut self, layer: &str) {
+        self.layer = Some(layer.to_string());
+    }
+
+    /// Record that this link took the place of an earlier layer's link to the same target
+    pub fn replace_layer(&mut self, layer: &str) {
+        self.replaced_layers.push(layer.to_string());
+    }
+
+    pub fn hooks(&self) -> &[usize] {
+        &self.hooks
+    }
+
+    /// The link's tags and layer, for status messages
+    fn describe(&self) -> String {
+        let mut details = Vec::new();
+        if !self.tags.is_empty() {
+            details.push(format!("tags: {}", self.tags.join(", ")));
+        }
+        if let Some(layer) = &self.layer {
+            let mut detail = format!("layer: {}", layer);
+            if !self.replaced_layers.is_empty() {
+                detail.push_str(&format!(", replacing {}", self.replaced_layers.join(", ")));
+            }
+            details.push(detail);
+        }
+        if details.is_empty() {
+            String::new()
+        } else {
+            format!(" [{}]", details.join("; "))
+        }
+    }
+
+    /// Create the link. The target must be free, or a broken symlink, which will be replaced
     pub fn link(&self) -> Result<()> {
         let dry_run = self.settings.dry_run();
-        if let Some(target_file_type) = self.target.file_type()? {
-            match target_file_type {
-                FileType::Dir => {
-                    todo!()
-                }
-                FileType::File => {
-                    todo!()
-                }
-                FileType::Symlink(points_to) => {
-                    if files_are_the_same(&self.src, &points_to).unwrap() {
-                        info!("{:?} is already linked to {:?}", self.src, self.target.path);
-                        // No need to continue and create the link
-                        return Ok(());
-                    } else {
-                        warn!(
-                            "Target is symlinked to {:?}, a different file than the defined source",
-                            self.target.path.canonicalize()
-                        );
-                        todo!();
-                    }
-                }
-                FileType::BrokenSymlink => {
-                    if dry_run {
-                        warn!(
-                            "Target {:?} is an existing broken symlink. Pretending to remove",
-                            self.target.path
-                        );
-                    } else {
-                        warn!(
-                            "Target {:?} is an existing broken symlink. Removing",
-                            self.target.path
-                        );
-                        fs::remove_file(&self.target.path).unwrap();
-                    }
-                }
-                FileType::Other(metadata) => {
-                    bail!(
-                        "Target {:?} exists and is an un-handable type: {:?}",
-                        self.target.path,
-                        metadata
-                    )
-                }
+        if let Some(FileType::BrokenSymlink) = self.target.file_type()? {
+            if dry_run {
+                warn!(
+                    "Target {:?} is an existing broken symlink. Pretending to remove",
+                    self.target.path
+                );
+            } else {
+                warn!(
+                    "Target {:?} is an existing broken symlink. Removing",
+                    self.target.path
+                );
+                fs::remove_file(&self.target.path)?;
             }
         }
 
+        self.decrypt()?;
         self.target.create_parent_dir(dry_run)?;
 
         if dry_run {
             info!(
                 "Pretending to link {:?} -> {:?}",
-                self.src, self.target.path
+                self.destination(),
+                self.target.path
             );
         } else {
-            info!("Linking {:?} -> {:?}", self.src, self.target.path);
-            std::os::unix::fs::symlink(&self.src, &self.target.path)?;
+            info!("Linking {:?} -> {:?}", self.destination(), self.target.path);
+            std::os::unix::fs::symlink(self.destination(), &self.target.path)?;
         }
 
         Ok(())
     }
 
+    /// Remove the link. The target must be a symlink to this link's source
     pub fn unlink(&self) -> Result<()> {
-        let dry_run = self.settings.dry_run();
-        let target_file_type = self.target.file_type()?;
-        match target_file_type {
-            Some(file_type) => match file_type {
-                FileType::Symlink(points_to) => {
-                    // TODO: dont unwrap
-                    if !files_are_the_same(&self.src, &points_to).unwrap() {
-                        debug!(
-                            "\n{}\n{}",
-                            self.src.canonicalize()?.display(),
-                            points_to.canonicalize()?.display()
-                        );
-                        bail!("Target points to something other than source. Source: {:?}, target: {:?}, target points to: {:?}", &self.target.path, self.src, points_to);
-                    }
-                    if dry_run {
-                        info!("Pretending to remove target {:?}", self.target.path);
-                    } else {
-                        info!("Removing target {:?}", self.target.path);
-                        fs::remove_file(&self.target.path)?;
-                    }
-                }
-                _ => {
-                    bail!(
-                        "Cannot remove target {:?}. It is not a symlink",
-                        self.target.path
-                    );
-                }
-            },
-            None => {
-                bail!(
-                    "Source file {:?} does not point to target {:?}. Cannot unlink",
-                    self.src,
-                    self.target.path
-                );
-            }
+        if self.settings.dry_run() {
+            info!("Pretending to remove target {:?}", self.target.path);
+        } else {
+            info!("Removing target {:?}", self.target.path);
+            fs::remove_file(&self.target.path)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the decrypted copy of an encrypted source
+    pub fn remove_decrypted(&self) -> Result<()> {
+        let Some(encryption) = &self.encryption else {
+            return Ok(());
+        };
+        if self.settings.dry_run() {
+            info!(
+                "Pretending to remove decrypted copy {:?}",
+                encryption.decrypted
+            );
+        } else if encryption.decrypted.exists() {
+            info!("Removing decrypted copy {:?}", encryption.decrypted);
+            encrypted::remove_decrypted(&encryption.decrypted)?;
         }
         Ok(())
     }
@@ -208,8 +374,19 @@ impl<'a> Link<'a> {
                     warn!("Target {:?} is a conflicting file", self.target.path);
                 }
                 FileType::Symlink(points_to) => {
-                    if files_are_the_same(&self.src, &points_to).unwrap() {
-                        info!("{:?} -> {:?}", self.src, self.target.path);
+                    if self.is_source(&points_to) {
+                        if self.is_stale()? {
+                            warn!(
+                                "Decrypted copy of {:?} is older than the source. Restow to decrypt it again",
+                                self.src
+                            );
+                        }
+                        info!(
+                            "{:?} -> {:?}{}",
+                            self.src,
+                            self.target.path,
+                            self.describe()
+                        );
                     } else {
                         warn!(
                             "Target is symlinked to {:?}, a different file than the defined source",
@@ -220,14 +397,24 @@ impl<'a> Link<'a> {
                 FileType::BrokenSymlink => {
                     warn!("Target is a broken symlink");
                 }
-                FileType::Other(_) => {
+                FileType::Other => {
                     warn!("Target is a conflicting file of an un-handable type")
                 }
             }
         } else {
-            info!("Source file {:?} is unlinked", self.src)
+            info!("Source file {:?} is unlinked{}", self.src, self.describe())
         };
 
         Ok(())
     }
+
+    pub fn skipped_status(&self, reason: &str) {
+        info!(
+            "Skipped {:?} -> {:?}{}: {}",
+            self.src,
+            self.target.path,
+            self.describe(),
+            reason
+        );
+    }
 }
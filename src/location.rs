@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// A position in a stowfile, used to point users at the entry an error is about
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    path: PathBuf,
+    line: Option<usize>,
+}
+impl Location {
+    pub fn new(path: &Path, line: Option<usize>) -> Self {
+        Location {
+            path: path.to_path_buf(),
+            line,
+        }
+    }
+}
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}", self.path.display(), line),
+            None => write!(f, "{}", self.path.display()),
+        }
+    }
+}
+
+/// The raw text of a stowfile, used to find line numbers for parsed values.
+/// serde_yaml does not keep track of where values came from, so instead we search the text.
+/// Values are looked up in the same order they appear in the file, so each lookup of the same
+/// text continues searching after the line of the previous match.
+#[derive(Debug)]
+pub struct SourceText {
+    path: PathBuf,
+    lines: Vec<String>,
+    next_search_line: HashMap<String, usize>,
+    next_definition_line: HashMap<String, usize>,
+}
+impl SourceText {
+    pub fn new(path: &Path, text: &str) -> Self {
+        SourceText {
+            path: path.to_path_buf(),
+            lines: text.lines().map(|line| line.to_string()).collect(),
+            next_search_line: HashMap::new(),
+            next_definition_line: HashMap::new(),
+        }
+    }
+
+    /// Find the first line from `start` that matches, returning its location and the index of the
+    /// line after it
+    fn find(&self, start: usize, matches: impl Fn(&str) -> bool) -> (Location, Option<usize>) {
+        let found = self
+            .lines
+            .iter()
+            .enumerate()
+            .skip(start)
+            .find(|(_, line)| matches(line))
+            .map(|(index, _)| index);
+        match found {
+            // Line numbers start at 1
+            Some(index) => (Location::new(&self.path, Some(index + 1)), Some(index + 1)),
+            None => (Location::new(&self.path, None), None),
+        }
+    }
+
+    pub fn locate(&mut self, needle: &str) -> Location {
+        let start = *self.next_search_line.get(needle).unwrap_or(&0);
+        let (location, next) = self.find(start, |line| line.contains(needle));
+        if let Some(next) = next {
+            self.next_search_line.insert(needle.to_string(), next);
+        }
+        location
+    }
+
+    /// Find where a variable is defined: a line starting with 'KEY=' or 'KEY:', optionally as a
+    /// list item or quoted. Comments are skipped, so a comment that mentions the variable doesn't
+    /// match
+    pub fn locate_definition(&mut self, key: &str) -> Location {
+        let defines = |line: &str| {
+            let line = line.trim_start();
+            if line.starts_with('#') {
+                return false;
+            }
+            let line = line.strip_prefix('-').unwrap_or(line).trim_start();
+            let line = line.trim_start_matches(['{', '"', '\'']);
+            line.strip_prefix(key)
+                .is_some_and(|rest| rest.starts_with(['=', ':']))
+        };
+        let start = *self.next_definition_line.get(key).unwrap_or(&0);
+        let (location, next) = self.find(start, defines);
+        if let Some(next) = next {
+            self.next_definition_line.insert(key.to_string(), next);
+        }
+        location
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STOWFILE: &str = "# A: is mentioned in a comment
+vars:
+  - A: ${B}
+  - \"B=${A}\"
+  - AB=1
+  - A=2
+";
+
+    #[test]
+    fn definitions_skip_comments() {
+        let mut source = SourceText::new(Path::new("stowfile"), STOWFILE);
+        assert_eq!(source.locate_definition("A").line, Some(3));
+        assert_eq!(source.locate_definition("B").line, Some(4));
+    }
+
+    #[test]
+    fn definitions_need_a_separator_after_the_key() {
+        let mut source = SourceText::new(Path::new("stowfile"), STOWFILE);
+        assert_eq!(source.locate_definition("AB").line, Some(5));
+        assert_eq!(source.locate_definition("C").line, None);
+    }
+
+    #[test]
+    fn later_definitions_of_a_key_are_found_in_order() {
+        let mut source = SourceText::new(Path::new("stowfile"), STOWFILE);
+        assert_eq!(source.locate_definition("A").line, Some(3));
+        assert_eq!(source.locate_definition("A").line, Some(6));
+    }
+}
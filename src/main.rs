@@ -1,21 +1,41 @@
-use anyhow::{bail, Result};
-use clap::{ArgAction, ArgGroup, Parser};
-use clap_verbosity_flag::{InfoLevel, Verbosity};
-use log::{debug, error, info, trace, warn};
-use std::env::{self, current_dir};
+use anyhow::{bail, Context, Result};
+use clap::{CommandFactory, FromArgMatches};
+use log::{debug, info, warn};
+use std::env;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+mod add;
+mod config;
+mod edit;
+mod encrypted;
+mod hooks;
+mod import;
+mod init;
 mod link;
+mod location;
+mod mv;
+mod plan;
+mod schema;
+#[cfg(test)]
+mod testing;
+mod vars;
+mod version;
+
+mod cli;
+use cli::{Action, Args};
+use config::Config;
+use edit::Document;
 
 mod stow;
 use stow::Stow;
 
 mod settings;
-use settings::Settings;
+use settings::{LinkSettings, Settings, StowfileSettings, VarSettings};
 
 mod filter;
-use filter::StowFilters;
+use filter::{StowFilters, TagFilter};
 
-const DEFAULT_STOWFILE_NAMES: &'static [&'static str] = &[
+const DEFAULT_STOWFILE_NAMES: &[&str] = &[
     "stowfile",
     "Stowfile",
     "STOWFILE",
@@ -24,102 +44,6 @@ const DEFAULT_STOWFILE_NAMES: &'static [&'static str] = &[
     "STOWFILE.yaml",
 ];
 
-/// New Stow - manage famrs of symbolic links with stowfiles
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-#[command(group(
-            ArgGroup::new("action")
-                .required(false)
-                .args(["stow", "unstow", "delete", "restow", "status"]),
-        ))]
-#[command(group(
-            ArgGroup::new("test")
-                .required(false)
-                .args(["dry_run", "simulate", "no"]),
-        ))]
-struct Args {
-    #[clap(flatten)]
-    verbose: Verbosity<InfoLevel>,
-
-    /// Sets a custom stowfilefile
-    #[arg(long, value_name = "FILE")]
-    stowfile: Option<PathBuf>,
-
-    /// Sets the working directory to "DIR" instead of the current directory.
-    /// Commands will be performed as if nstow was invoked from this directory.
-    /// When combined with '--stowfile' paths in the stowfile will be intrepreted relative to
-    /// "DIR", rather than the current working directory.
-    #[arg(short, long, value_name = "DIR")]
-    dir: Option<PathBuf>,
-
-    /// Do not perform any actions, just show what would be done.
-    /// This option is useless when combined with '-qqq'.
-    #[arg(long)]
-    dry_run: bool,
-
-    /// Alias for '--dry-run' to preserve some compatibility with GNU Stow.
-    #[arg(short, long)]
-    no: bool,
-
-    /// Alias for '--dry-run' to preserve some compatibility with GNU Stow.
-    #[arg(long)]
-    simulate: bool,
-
-    /// Remove previously created symlinks.
-    #[arg(long)]
-    unstow: bool,
-
-    /// Alias for '--unstow' arg. Used to preserve some compatibility with GNU Stow.
-    #[arg(short = 'D', long)]
-    delete: bool,
-
-    /// Create symlinks specified by the stowfile.
-    /// This is the default action when no other action is requested.
-    #[arg(short = 'S', long)]
-    stow: bool,
-
-    /// Restow by deleting any existing links before linking again.
-    /// Shortcut for 'nstow --unstow && nstow --stow'
-    #[arg(short = 'R', long)]
-    restow: bool,
-
-    /// Show the status of links in the stowfile.
-    #[arg(long, short)]
-    status: bool,
-
-    /// Ignore source files that match this regex.
-    /// This flag may be passed multiple times and combined with '--only'.
-    #[arg(long, value_name = "REGEX", action = ArgAction::Append)]
-    ignore: Option<Vec<String>>,
-
-    /// Only stow source files that match this regex.
-    /// This flag may be passed multiple times and combined with '--ignore'.
-    #[arg(long, value_name = "REGEX", action = ArgAction::Append)]
-    only: Option<Vec<String>>,
-
-    /// Ignore targets that match this regex.
-    /// This flag may be passed multiple times and combined with '--only-target'.
-    #[arg(long, value_name = "REGEX", action = ArgAction::Append)]
-    ignore_target: Option<Vec<String>>,
-
-    /// Only stow targets that match this regex.
-    /// This flag may be passed multiple times and combined with '--ignore-target'.
-    #[arg(long, value_name = "REGEX", action = ArgAction::Append)]
-    only_target: Option<Vec<String>>,
-
-    /// Sets a directory for backuping up any existing files at target locations.
-    /// This option may be used with '--override', in which case all files are backed up except
-    /// those marked for overriding.
-    #[arg(short, long, value_name = "BACKUP_DIR")]
-    backup: Option<PathBuf>, //TODO: set default location
-
-    /// Force overriding of any existing targets or files at target locations.
-    #[arg(long, value_name = "REGEX", action = ArgAction::Append)]
-    r#override: Option<Vec<String>>,
-    // TODO: add a '--restore' flag to restore the last backups when unstowing
-    // Save the last backups in subdir and restore files from that subdir
-}
-
 fn check_for_default_stowfile(working_dir: &Path) -> Option<PathBuf> {
     for name in DEFAULT_STOWFILE_NAMES {
         let mut stowfile = working_dir.to_path_buf();
@@ -133,71 +57,326 @@ fn check_for_default_stowfile(working_dir: &Path) -> Option<PathBuf> {
     None
 }
 
+/// Look for a stowfile in the working dir, then in each of its parents, like git looks for a repo.
+/// The search stops at the root of a git repo, so a stowfile outside of the repo is never used
+fn find_stowfile(working_dir: &Path) -> Result<Option<PathBuf>> {
+    let working_dir = working_dir
+        .canonicalize()
+        .with_context(|| format!("Unable to resolve working dir {:?}", working_dir))?;
+    for dir in working_dir.ancestors() {
+        if let Some(stowfile) = check_for_default_stowfile(dir) {
+            return Ok(Some(stowfile));
+        }
+        if dir.join(".git").exists() {
+            debug!("Stopping the search for a stowfile at git repo {:?}", dir);
+            break;
+        }
+    }
+    Ok(None)
+}
+
+/// Find every stowfile in a directory and its subdirectories, in a stable order. Hidden
+/// directories, like '.git', are not searched
+fn find_stowfiles_under(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut stowfiles = Vec::new();
+    if let Some(stowfile) = check_for_default_stowfile(dir) {
+        stowfiles.push(stowfile);
+    }
+    let mut subdirs = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Unable to read {:?}", dir))? {
+        let entry = entry?;
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        // Symlinked directories are skipped, since they may lead back up the tree
+        if entry.file_type()?.is_dir() && !hidden {
+            subdirs.push(entry.path());
+        }
+    }
+    subdirs.sort();
+    for subdir in subdirs {
+        stowfiles.append(&mut find_stowfiles_under(&subdir)?);
+    }
+    Ok(stowfiles)
+}
+
+/// The stowfile of a '--layer', which is either a stow dir or a stowfile
+fn layer_stowfile(working_dir: &Path, layer: &Path) -> Result<StowfileSettings> {
+    let path = working_dir.join(layer);
+    let stowfile = if path.is_dir() {
+        let Some(stowfile) = check_for_default_stowfile(&path) else {
+            bail!("Unable to find a stowfile in layer {:?}", layer);
+        };
+        stowfile
+    } else if path.exists() {
+        path
+    } else {
+        bail!("Layer {:?} does not exist", layer);
+    };
+    let dir = stowfile.parent().map(Path::to_path_buf).unwrap_or_default();
+    Ok(StowfileSettings::layer(
+        stowfile,
+        dir,
+        layer.display().to_string(),
+    ))
+}
+
+/// Write a stowfile for the files in the working dir, refusing to replace an existing one unless
+/// forced
+fn init_stowfile(
+    working_dir: &Path,
+    stowfile: Option<PathBuf>,
+    target: &str,
+    force: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let existing = match &stowfile {
+        Some(stowfile) => Some(stowfile.clone()).filter(|stowfile| stowfile.exists()),
+        None => check_for_default_stowfile(working_dir),
+    };
+    if let Some(existing) = &existing {
+        if !force {
+            bail!(
+                "Stowfile {:?} already exists. Pass '--force' to overwrite it",
+                existing
+            );
+        }
+    }
+    let stowfile = stowfile
+        .or(existing)
+        .unwrap_or_else(|| working_dir.join(DEFAULT_STOWFILE_NAMES[0]));
+
+    // The stowfile itself is never a source
+    let mut skip = DEFAULT_STOWFILE_NAMES.to_vec();
+    if let Some(name) = stowfile.file_name().and_then(|name| name.to_str()) {
+        skip.push(name);
+    }
+    let contents = init::generate_stowfile(working_dir, target, &skip)?;
+    if dry_run {
+        info!("Pretending to write stowfile {:?}:\n{}", stowfile, contents);
+    } else {
+        info!("Writing stowfile {:?}", stowfile);
+        std::fs::write(&stowfile, contents)?;
+    }
+    Ok(())
+}
+
+/// Rewrite the stowfile in the canonical layout. With `check`, fail instead if it isn't already
+fn format_stowfile(stowfile: &Path, check: bool, sort: bool, dry_run: bool) -> Result<()> {
+    let original = std::fs::read_to_string(stowfile)?;
+    let formatted = Document::parse(&original)?.format(sort)?.into_text()?;
+    if formatted == original {
+        info!("{:?} is already formatted", stowfile);
+        return Ok(());
+    }
+    if check {
+        bail!(
+            "{:?} is not formatted. Run 'nstow fmt' to format it",
+            stowfile
+        );
+    }
+    if dry_run {
+        print!("{}", formatted);
+        return Ok(());
+    }
+    info!("Formatting {:?}", stowfile);
+    std::fs::write(stowfile, formatted)?;
+    Ok(())
+}
+
+/// Print the settings and where they came from. Output piped into a command that exits early,
+/// like 'grep -q', is not an error
+fn print_config(config: &Config) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    match write!(stdout, "{}", config).and_then(|_| stdout.flush()) {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => Ok(result?),
+    }
+}
+
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    if let Err(e) =
+        Args::check_subcommand_conflicts(&matches).and_then(|_| Args::check_change_flags(&matches))
+    {
+        e.exit();
+    }
+    let args = Args::from_arg_matches(&matches)?;
     env_logger::Builder::new()
         .filter_level(args.verbose.log_level_filter())
         .init();
 
     let actual_current_working_dir = env::current_dir()?;
 
-    // Grab the working dir from the user's input input arg. Fallback to the actual current working dir
-    let working_dir = match args.dir {
-        Some(working_dir) => working_dir,
-        None => actual_current_working_dir.clone(),
+    let dry_run = args.dry_run || args.simulate || args.no;
+    let var_settings = VarSettings::new(
+        args.var.clone().unwrap_or_default(),
+        args.vars_file.clone().unwrap_or_default(),
+        args.allow_commands,
+    );
+    let identity = args.identity.clone();
+    let allow_hooks = args.allow_hooks;
+    let stowfile_arg = args.stowfile.clone();
+    let recursive = args.recursive;
+    let layers = args.layer.clone().unwrap_or_default();
+    let dir_arg = args.dir.clone();
+    let (action, selection, link_args) = args.into_action(&matches);
+
+    // Options not given on the command line come from the environment or the config file
+    let target_arg = match &action {
+        Action::Init { target, .. } => target.clone(),
+        _ => None,
     };
+    let config = Config::load(&actual_current_working_dir, dir_arg, target_arg)?;
+
+    // Grab the working dir from the user's input arg or the config. Fallback to the actual
+    // current working dir
+    let mut working_dir = config.dir().value().clone();
     working_dir.canonicalize()?;
     debug!("Working dir: {}", working_dir.display());
 
-    // Grab the stowfile from user's input arg. Fallback to a stowfile in the current dir
-    let stowfile_path = match args.stowfile {
-        Some(stowfile_path) => {
-            if stowfile_path.try_exists().is_err() {
-                bail!(
-                    "Specified stowfile file ({}) does not exist.",
-                    stowfile_path.display()
-                );
+    // Only commands that read the stowfiles can use more than one of them
+    if (recursive || !layers.is_empty())
+        && !matches!(
+            action,
+            Action::Apply(_) | Action::Status(_) | Action::Validate
+        )
+    {
+        let flag = if recursive { "--recursive" } else { "--layer" };
+        bail!(
+            "'{}' can only be used to stow, unstow, restow, check status, or validate",
+            flag
+        );
+    }
+
+    // Printing the schema or the config, and actions that create stowfiles, don't need a stowfile
+    // to exist
+    match action {
+        Action::Config => return print_config(&config),
+        Action::Schema => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&stow::stowfile_schema())?
+            );
+            return Ok(());
+        }
+        Action::Init { force, .. } => {
+            return init_stowfile(
+                &working_dir,
+                stowfile_arg,
+                config.target().value(),
+                force,
+                dry_run,
+            );
+        }
+        Action::Import {
+            ref roots,
+            write: false,
+        } => {
+            match import::import_links(roots, &working_dir)? {
+                Some(entries) => print!("{}", entries),
+                None => warn!("No symlinks into {:?} were found", working_dir),
             }
-            stowfile_path
+            return Ok(());
         }
-        None => {
-            // Try to find a stowfile in the current directory
-            let Some(stowfile_path) = check_for_default_stowfile(&working_dir) else {
-                bail!("Unable to find stowfile in the working directory");
-            // TODO: fall back to gnu stow's behavior when no stowfile is present?
-            };
-            stowfile_path
+        _ => {}
+    }
+
+    // Each stowfile found recursively, and each layer, has its own directory as the source root
+    let stowfiles = if !layers.is_empty() {
+        layers
+            .iter()
+            .map(|layer| layer_stowfile(&working_dir, layer))
+            .collect::<Result<Vec<_>>>()?
+    } else if recursive {
+        let stowfiles = find_stowfiles_under(&working_dir.canonicalize()?)?;
+        if stowfiles.is_empty() {
+            bail!("Unable to find any stowfiles under the working directory");
         }
+        stowfiles
+            .into_iter()
+            .map(|stowfile| {
+                info!("Using stowfile {:?}", stowfile);
+                let dir = stowfile.parent().map(Path::to_path_buf).unwrap_or_default();
+                StowfileSettings::new(stowfile, dir)
+            })
+            .collect()
+    } else {
+        // Grab the stowfile from user's input arg. Fallback to a stowfile in the current dir
+        let stowfile_path = match stowfile_arg {
+            Some(stowfile_path) => {
+                if stowfile_path.try_exists().is_err() {
+                    bail!(
+                        "Specified stowfile file ({}) does not exist.",
+                        stowfile_path.display()
+                    );
+                }
+                stowfile_path
+            }
+            None => {
+                // Try to find a stowfile in the current directory or one of its parents
+                let Some(stowfile_path) = find_stowfile(&working_dir)? else {
+                    bail!("Unable to find stowfile in the working directory or its parents");
+                    // TODO: fall back to gnu stow's behavior when no stowfile is present?
+                };
+                // Sources are relative to the directory the stowfile was found in
+                let canonical_working_dir = working_dir.canonicalize()?;
+                if let Some(stowfile_dir) = stowfile_path
+                    .parent()
+                    .filter(|dir| *dir != canonical_working_dir)
+                {
+                    info!("Using stowfile {:?}", stowfile_path);
+                    working_dir = stowfile_dir.to_path_buf();
+                }
+                stowfile_path
+            }
+        };
+        debug!("Stowfile: {}", stowfile_path.display());
+        vec![StowfileSettings::new(stowfile_path, working_dir.clone())]
     };
-    debug!("Stowfile: {}", stowfile_path.display());
 
-    let mut default_backup_location = actual_current_working_dir;
-    default_backup_location.push("backups");
+    // Formatting and migrating only work on the stowfile's text, so the stowfile doesn't have to
+    // load
+    match action {
+        Action::Format { check, sort } => {
+            return format_stowfile(stowfiles[0].path(), check, sort, dry_run);
+        }
+        Action::Migrate => return version::migrate_stowfile(stowfiles[0].path(), dry_run),
+        _ => {}
+    }
 
     let filters = StowFilters::new(
-        args.only,
-        args.ignore,
-        args.only_target,
-        args.ignore_target,
-        args.r#override,
+        selection.only,
+        selection.ignore,
+        selection.only_target,
+        selection.ignore_target,
+        link_args.r#override,
+        TagFilter::new(
+            selection.tag.unwrap_or_default(),
+            selection.skip_tag.unwrap_or_default(),
+        ),
+    );
+    let settings = Settings::new(
+        stowfiles,
+        working_dir,
+        LinkSettings::new(dry_run, link_args.backup, identity, allow_hooks),
+        filters,
+        var_settings,
     );
-    let dry_run = args.dry_run || args.simulate || args.no;
-    let settings = Settings::new(stowfile_path, working_dir, dry_run, args.backup, filters);
     let app = Stow::with_settings(&settings)?;
 
-    match (
-        args.stow,
-        args.unstow || args.delete,
-        args.restow,
-        args.status,
-    ) {
-        (false, false, false, false) => app.stow()?,
-        (true, false, false, false) => app.stow()?,
-        (false, true, false, false) => app.unstow()?,
-        (false, false, true, false) => app.restow()?,
-        (false, false, false, true) => app.status()?,
-        _ => {
-            bail!("Only one action may be specified");
+    match action {
+        Action::Apply(operations) => app.apply(&operations)?,
+        Action::Status(packages) => app.status(&packages)?,
+        Action::Validate => app.validate(),
+        Action::Add { file, source } => add::add_file(&settings, &file, source)?,
+        Action::Move { from, to } => mv::move_source(&settings, &app, &from, &to)?,
+        Action::Import { roots, .. } => import::write_links(&settings, &roots)?,
+        Action::SetVar { definition } => edit::set_var(&settings, &definition)?,
+        Action::UnsetVar { key } => edit::unset_var(&settings, &key)?,
+        Action::Init { .. } | Action::Schema | Action::Config => {
+            unreachable!("actions that don't need a stowfile are handled before loading one")
+        }
+        Action::Format { .. } | Action::Migrate => {
+            unreachable!("formatting and migrating are handled before loading the stowfile")
         }
     }
 
@@ -0,0 +1,176 @@
+use crate::edit::{restore_stowfile, write_stowfile, Document};
+use crate::link::{Link, TargetState};
+use crate::settings::Settings;
+use crate::stow::Stow;
+use crate::vars::escape_variables;
+use anyhow::{bail, Context, Result};
+use log::{error, info, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A path given relative to the stow dir, along with how it is written as a source
+fn source_path(stow_dir: &Path, path: &Path) -> Result<(PathBuf, String)> {
+    let relative = match path.strip_prefix(stow_dir) {
+        Ok(relative) => relative,
+        Err(_) if path.is_absolute() => bail!("{:?} is not in the stow dir {:?}", path, stow_dir),
+        Err(_) => path,
+    };
+    if relative.as_os_str().is_empty() {
+        bail!("{:?} is the stow dir itself", path);
+    }
+    Ok((
+        stow_dir.join(relative),
+        escape_variables(&relative.display().to_string()),
+    ))
+}
+
+/// Check if a link's target is currently a symlink to its source
+fn is_linked(link: &Link) -> Result<bool> {
+    Ok(match link.target_state()? {
+        TargetState::LinkedTo(points_to) => link.is_source(&points_to),
+        _ => false,
+    })
+}
+
+/// Move a source within the stow dir and change its entry to match. Links to the old path are
+/// removed before the move and made again afterwards. If any step fails, the steps before it are
+/// undone. Directories left empty by the move are removed
+pub fn move_source(settings: &Settings, app: &Stow, from: &Path, to: &Path) -> Result<()> {
+    let stow_dir = settings.current_working_dir();
+    let (from_path, from_src) = source_path(stow_dir, from)?;
+    let (to_path, to_src) = source_path(stow_dir, to)?;
+    fs::symlink_metadata(&from_path).with_context(|| format!("Unable to move {:?}", from_path))?;
+    if to_path.exists() {
+        bail!("{:?} already exists", to_path);
+    }
+
+    let stowfile_path = settings.stowfile_path();
+    let original = fs::read_to_string(stowfile_path)?;
+    let mut stowfile = Document::parse(&original)?;
+    stowfile.move_entry(&from_src, &to_src)?;
+    let updated = stowfile.into_text()?;
+
+    let mut linked = Vec::new();
+    for link in app.links_from(&from_path) {
+        if is_linked(link)? {
+            linked.push(link);
+        }
+    }
+
+    if settings.link_settings().dry_run() {
+        for link in &linked {
+            link.unlink()?;
+        }
+        info!("Pretending to move {:?} to {:?}", from_path, to_path);
+        info!(
+            "Pretending to change {:?} to {:?} in {:?}",
+            from_src, to_src, stowfile_path
+        );
+        return Ok(());
+    }
+
+    let relink = |links: &[&Link]| {
+        for link in links {
+            if let Err(e) = link.link() {
+                error!("Unable to link {:?}: {}", link.target(), e);
+            }
+        }
+    };
+    for (count, link) in linked.iter().enumerate() {
+        if let Err(e) = link.unlink() {
+            relink(&linked[..count]);
+            return Err(e);
+        }
+    }
+
+    // Parent dirs that have to be created for the new path, outermost first
+    let mut created_dirs: Vec<PathBuf> = to_path
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| !dir.exists())
+        .map(Path::to_path_buf)
+        .collect();
+    created_dirs.reverse();
+    let undo = || {
+        info!("Moving {:?} back to {:?}", to_path, from_path);
+        if let Err(e) = fs::rename(&to_path, &from_path) {
+            error!(
+                "Unable to move {:?} back to {:?}: {}",
+                to_path, from_path, e
+            );
+        }
+        for dir in created_dirs.iter().rev() {
+            if let Err(e) = fs::remove_dir(dir) {
+                error!("Unable to remove {:?}: {}", dir, e);
+            }
+        }
+        relink(&linked);
+    };
+
+    if let Some(parent) = to_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    info!("Moving {:?} to {:?}", from_path, to_path);
+    if let Err(e) = fs::rename(&from_path, &to_path) {
+        // Nothing was moved, but the links and any created dirs still have to be put back
+        for dir in created_dirs.iter().rev() {
+            let _ = fs::remove_dir(dir);
+        }
+        relink(&linked);
+        return Err(e).with_context(|| format!("Unable to move {:?} to {:?}", from_path, to_path));
+    }
+
+    info!(
+        "Changing {:?} to {:?} in {:?}",
+        from_src, to_src, stowfile_path
+    );
+    if let Err(e) = write_stowfile(settings, &original, &updated) {
+        undo();
+        return Err(e);
+    }
+
+    // Link the targets that were linked before, from the new path
+    let moved = match Stow::with_settings(settings) {
+        Ok(moved) => moved,
+        Err(e) => {
+            restore_stowfile(settings, &original);
+            undo();
+            return Err(e);
+        }
+    };
+    let targets: Vec<&Path> = linked.iter().map(|link| link.target()).collect();
+    let mut relinked: Vec<&Link> = Vec::new();
+    for link in moved.links_from(&to_path) {
+        if !targets.contains(&link.target()) {
+            continue;
+        }
+        if let Err(e) = link.link() {
+            for link in &relinked {
+                if let Err(e) = link.unlink() {
+                    error!("Unable to unlink {:?}: {}", link.target(), e);
+                }
+            }
+            restore_stowfile(settings, &original);
+            undo();
+            return Err(e);
+        }
+        relinked.push(link);
+    }
+
+    // The move is done, so directories it emptied can go
+    for dir in from_path
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| *dir != stow_dir && dir.starts_with(stow_dir))
+    {
+        if fs::read_dir(dir)?.next().is_some() {
+            break;
+        }
+        info!("Removing empty directory {:?}", dir);
+        if let Err(e) = fs::remove_dir(dir) {
+            warn!("Unable to remove {:?}: {}", dir, e);
+            break;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,435 @@
+use crate::hooks::{HookKind, Hooks};
+use crate::link::{Link, TargetState};
+use anyhow::{bail, Result};
+use log::{debug, error, info};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+
+/// A change to make to the links selected by an operation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    Stow,
+    Unstow,
+    Restow,
+}
+
+/// A change applied to some packages, or to every entry in the stowfile when no packages are given
+#[derive(Debug)]
+pub struct Operation {
+    change: Change,
+    packages: Vec<String>,
+}
+impl Operation {
+    pub fn new(change: Change, packages: Vec<String>) -> Self {
+        Operation { change, packages }
+    }
+
+    pub fn change(&self) -> Change {
+        self.change
+    }
+
+    pub fn packages(&self) -> &[String] {
+        &self.packages
+    }
+}
+
+#[derive(Debug)]
+enum Step<'l, 'a> {
+    Link(&'l Link<'a>),
+    Unlink(&'l Link<'a>),
+    Decrypt(&'l Link<'a>),
+    RemoveDecrypted(&'l Link<'a>),
+}
+
+/// Every link and unlink from all of the requested operations, in order.
+/// Targets are checked against the state left by the earlier steps rather than just what is on
+/// disk, so nothing is changed unless the whole plan can be carried out. This way unstowing a
+/// package and stowing another that takes over its targets is not a conflict.
+#[derive(Debug, Default)]
+pub struct Plan<'l, 'a> {
+    steps: Vec<Step<'l, 'a>>,
+    // Targets as they will be after the steps so far
+    planned: HashMap<PathBuf, TargetState>,
+    conflicts: Vec<String>,
+}
+impl<'l, 'a> Plan<'l, 'a> {
+    pub fn new() -> Self {
+        Plan::default()
+    }
+
+    fn state(&self, link: &Link) -> Result<TargetState> {
+        match self.planned.get(link.target()) {
+            Some(state) => Ok(state.clone()),
+            None => link.target_state(),
+        }
+    }
+
+    /// Plan to remove a link. When restowing a link that was never stowed there is nothing to do,
+    /// otherwise the target must be a symlink to the link's source
+    pub fn unlink(&mut self, link: &'l Link<'a>, must_exist: bool) -> Result<()> {
+        match self.state(link)? {
+            TargetState::LinkedTo(points_to) if link.is_source(&points_to) => {
+                self.steps.push(Step::Unlink(link));
+                self.planned
+                    .insert(link.target().to_path_buf(), TargetState::Missing);
+            }
+            TargetState::Missing if !must_exist => {
+                debug!("{:?} is not linked, nothing to unlink", link.src());
+            }
+            TargetState::Missing => self.conflicts.push(format!(
+                "Source file {:?} does not point to target {:?}. Cannot unlink",
+                link.src(),
+                link.target()
+            )),
+            TargetState::LinkedTo(points_to) => self.conflicts.push(format!(
+                "Target {:?} points to {:?} rather than source {:?}. Cannot unlink",
+                link.target(),
+                points_to,
+                link.src()
+            )),
+            other => self.conflicts.push(format!(
+                "Cannot remove target {:?}. It is {}, not a symlink",
+                link.target(),
+                other
+            )),
+        }
+        Ok(())
+    }
+
+    /// Plan to create a link. Broken symlinks at the target are replaced
+    pub fn link(&mut self, link: &'l Link<'a>) -> Result<()> {
+        match self.state(link)? {
+            TargetState::Missing | TargetState::BrokenSymlink => {
+                if let Some(problem) = link.decryption_problem() {
+                    self.conflicts.push(problem);
+                    return Ok(());
+                }
+                self.steps.push(Step::Link(link));
+                self.planned.insert(
+                    link.target().to_path_buf(),
+                    TargetState::LinkedTo(link.destination().to_path_buf()),
+                );
+            }
+            // Encrypted sources that changed since they were decrypted are decrypted again
+            TargetState::LinkedTo(points_to)
+                if link.is_source(&points_to) && link.is_stale()? =>
+            {
+                match link.decryption_problem() {
+                    Some(problem) => self.conflicts.push(problem),
+                    None => self.steps.push(Step::Decrypt(link)),
+                }
+            }
+            TargetState::LinkedTo(points_to) if link.is_source(&points_to) => {
+                info!("{:?} is already linked to {:?}", link.src(), link.target());
+            }
+            other => self.conflicts.push(format!(
+                "Cannot link {:?} -> {:?}. The target is {}",
+                link.src(),
+                link.target(),
+                other
+            )),
+        }
+        Ok(())
+    }
+
+    /// Plan to remove the decrypted copies of the encrypted sources that are unlinked, unless
+    /// another of the links still uses the same copy once the plan is carried out
+    pub fn remove_unused_decrypted(&mut self, links: &[&'l Link<'a>]) -> Result<()> {
+        let unlinked: Vec<&'l Link<'a>> = self
+            .steps
+            .iter()
+            .filter_map(|step| match step {
+                Step::Unlink(link) if link.is_encrypted() => Some(*link),
+                _ => None,
+            })
+            .collect();
+        let mut handled = HashSet::new();
+        for link in unlinked {
+            let copy = link.destination();
+            if !handled.insert(copy) {
+                continue;
+            }
+            let mut users = Vec::new();
+            for other in links.iter().filter(|other| other.destination() == copy) {
+                if let TargetState::LinkedTo(points_to) = self.state(other)? {
+                    if other.is_source(&points_to) {
+                        users.push(other.target());
+                    }
+                }
+            }
+            if users.is_empty() {
+                self.steps.push(Step::RemoveDecrypted(link));
+            } else {
+                debug!(
+                    "Keeping decrypted copy {:?}, since it is still linked to from {:?}",
+                    copy, users
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Indices of the hooks covering the links that are stowed and unstowed, outermost first
+    fn hooks_to_run(&self) -> (BTreeSet<usize>, BTreeSet<usize>) {
+        let mut stow_hooks = BTreeSet::new();
+        let mut unstow_hooks = BTreeSet::new();
+        for step in &self.steps {
+            match step {
+                Step::Link(link) | Step::Decrypt(link) => stow_hooks.extend(link.hooks()),
+                Step::Unlink(link) => unstow_hooks.extend(link.hooks()),
+                Step::RemoveDecrypted(_) => {}
+            }
+        }
+        (stow_hooks, unstow_hooks)
+    }
+
+    /// Carry out the plan, or report every conflict without changing anything.
+    /// Pre hooks run before anything is changed, from the stowfile's down to the innermost
+    /// node's, and post hooks run in the opposite order once everything is linked
+    pub fn execute(self, hooks: &[Hooks]) -> Result<()> {
+        if !self.conflicts.is_empty() {
+            bail!(
+                "Nothing was changed because of {} conflict(s):\n  {}",
+                self.conflicts.len(),
+                self.conflicts.join("\n  ")
+            );
+        }
+
+        let (stow_hooks, unstow_hooks) = self.hooks_to_run();
+        for index in &unstow_hooks {
+            hooks[*index].run(HookKind::PreUnstow)?;
+        }
+        for index in &stow_hooks {
+            hooks[*index].run(HookKind::PreStow)?;
+        }
+
+        for step in self.steps {
+            match step {
+                Step::Link(link) => link.link()?,
+                Step::Unlink(link) => link.unlink()?,
+                Step::Decrypt(link) => link.decrypt()?,
+                Step::RemoveDecrypted(link) => link.remove_decrypted()?,
+            }
+        }
+
+        // The links are already in place, so run every post hook even if one of them fails
+        let mut failures = 0;
+        let post_hooks = unstow_hooks
+            .iter()
+            .rev()
+            .map(|index| (index, HookKind::PostUnstow))
+            .chain(
+                stow_hooks
+                    .iter()
+                    .rev()
+                    .map(|index| (index, HookKind::PostStow)),
+            );
+        for (index, kind) in post_hooks {
+            if let Err(e) = hooks[*index].run(kind) {
+                error!("{}", e);
+                failures += 1;
+            }
+        }
+        if failures > 0 {
+            bail!("{} hook(s) failed after changing links", failures);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hooks::Hooks;
+    use crate::link::Encryption;
+    use crate::settings::LinkSettings;
+    use crate::testing::scratch_dir;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+
+    fn encrypted_link<'a>(dir: &Path, target: &str, settings: &'a LinkSettings) -> Link<'a> {
+        Link::new(
+            dir.join("secret.age").display().to_string(),
+            dir.join(target).display().to_string(),
+            Vec::new(),
+            None,
+            Vec::new(),
+            Some(Encryption::at(
+                dir.join("secret"),
+                Some(dir.join("key.txt")),
+            )),
+            settings,
+        )
+        .unwrap()
+    }
+
+    fn removes_decrypted(plan: &Plan) -> usize {
+        plan.steps
+            .iter()
+            .filter(|step| matches!(step, Step::RemoveDecrypted(_)))
+            .count()
+    }
+
+    #[test]
+    fn decrypted_copy_is_only_removed_once_nothing_links_to_it() {
+        let dir = scratch_dir("plan-decrypted");
+        fs::write(dir.join("secret.age"), "encrypted").unwrap();
+        fs::write(dir.join("secret"), "plaintext").unwrap();
+        symlink(dir.join("secret"), dir.join("first")).unwrap();
+        symlink(dir.join("secret"), dir.join("second")).unwrap();
+        let settings = LinkSettings::new(false, None, None, false);
+        let first = encrypted_link(&dir, "first", &settings);
+        let second = encrypted_link(&dir, "second", &settings);
+        let links = [&first, &second];
+
+        let mut plan = Plan::new();
+        plan.unlink(&first, true).unwrap();
+        plan.remove_unused_decrypted(&links).unwrap();
+        assert_eq!(removes_decrypted(&plan), 0);
+
+        let mut plan = Plan::new();
+        plan.unlink(&first, true).unwrap();
+        plan.unlink(&second, true).unwrap();
+        plan.remove_unused_decrypted(&links).unwrap();
+        assert_eq!(removes_decrypted(&plan), 1);
+
+        // Restowing puts the link back, so the copy is still used
+        let mut plan = Plan::new();
+        plan.unlink(&first, false).unwrap();
+        plan.link(&first).unwrap();
+        plan.unlink(&second, true).unwrap();
+        plan.remove_unused_decrypted(&links).unwrap();
+        assert_eq!(removes_decrypted(&plan), 0);
+    }
+
+    /// Hooks that log which hook ran for which node, with any of them swapped for a command
+    fn logging_hooks<'a>(
+        node: &str,
+        dir: &Path,
+        replaced: &[(&str, &str)],
+        settings: &'a LinkSettings,
+    ) -> Hooks<'a> {
+        let yaml: String = ["pre_stow", "post_stow", "pre_unstow", "post_unstow"]
+            .iter()
+            .map(|kind| {
+                let command = match replaced.iter().find(|(k, _)| k == kind) {
+                    Some((_, command)) => command.to_string(),
+                    None => format!("echo {} {} >> log", kind, node),
+                };
+                format!("{}: {}\n", kind, command)
+            })
+            .collect();
+        let yaml = serde_yaml::from_str(&yaml).unwrap();
+        Hooks::from_yaml(&yaml, node, Vec::new(), dir, settings).unwrap()
+    }
+
+    fn plain_link<'a>(dir: &Path, settings: &'a LinkSettings) -> Link<'a> {
+        fs::write(dir.join("fonts.conf"), "").unwrap();
+        Link::new(
+            dir.join("fonts.conf").display().to_string(),
+            dir.join("target").display().to_string(),
+            Vec::new(),
+            None,
+            vec![0, 1],
+            None,
+            settings,
+        )
+        .unwrap()
+    }
+
+    fn hook_log(dir: &Path) -> Vec<String> {
+        fs::read_to_string(dir.join("log"))
+            .unwrap_or_default()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn pre_hooks_run_outermost_first_and_post_hooks_innermost_first() {
+        let dir = scratch_dir("plan-hook-order");
+        let settings = LinkSettings::new(false, None, None, true);
+        let hooks = [
+            logging_hooks("stowfile", &dir, &[], &settings),
+            logging_hooks("fonts", &dir, &[], &settings),
+        ];
+        let link = plain_link(&dir, &settings);
+
+        let mut plan = Plan::new();
+        plan.link(&link).unwrap();
+        plan.execute(&hooks).unwrap();
+        let mut plan = Plan::new();
+        plan.unlink(&link, true).unwrap();
+        plan.execute(&hooks).unwrap();
+
+        assert_eq!(
+            hook_log(&dir),
+            [
+                "pre_stow stowfile",
+                "pre_stow fonts",
+                "post_stow fonts",
+                "post_stow stowfile",
+                "pre_unstow stowfile",
+                "pre_unstow fonts",
+                "post_unstow fonts",
+                "post_unstow stowfile",
+            ]
+        );
+    }
+
+    #[test]
+    fn failing_pre_hook_skips_the_steps_and_the_post_hooks() {
+        let dir = scratch_dir("plan-pre-hook-fails");
+        let settings = LinkSettings::new(false, None, None, true);
+        let hooks = [
+            logging_hooks("stowfile", &dir, &[], &settings),
+            logging_hooks("fonts", &dir, &[("pre_stow", "exit 1")], &settings),
+        ];
+        let link = plain_link(&dir, &settings);
+
+        let mut plan = Plan::new();
+        plan.link(&link).unwrap();
+        assert!(plan.execute(&hooks).is_err());
+        assert!(!dir.join("target").exists());
+        assert_eq!(hook_log(&dir), ["pre_stow stowfile"]);
+    }
+
+    #[test]
+    fn failing_post_hook_still_runs_the_other_post_hooks() {
+        let dir = scratch_dir("plan-post-hook-fails");
+        let settings = LinkSettings::new(false, None, None, true);
+        let hooks = [
+            logging_hooks("stowfile", &dir, &[], &settings),
+            logging_hooks("fonts", &dir, &[("post_stow", "exit 1")], &settings),
+        ];
+        let link = plain_link(&dir, &settings);
+
+        let mut plan = Plan::new();
+        plan.link(&link).unwrap();
+        let error = plan.execute(&hooks).unwrap_err();
+        assert_eq!(error.to_string(), "1 hook(s) failed after changing links");
+        assert!(dir.join("target").is_symlink());
+        assert_eq!(
+            hook_log(&dir),
+            ["pre_stow stowfile", "pre_stow fonts", "post_stow stowfile"]
+        );
+    }
+
+    #[test]
+    fn hooks_are_skipped_unless_allowed() {
+        let dir = scratch_dir("plan-hooks-not-allowed");
+        let settings = LinkSettings::new(false, None, None, false);
+        let hooks = [
+            logging_hooks("stowfile", &dir, &[], &settings),
+            logging_hooks("fonts", &dir, &[("pre_stow", "exit 1")], &settings),
+        ];
+        let link = plain_link(&dir, &settings);
+
+        let mut plan = Plan::new();
+        plan.link(&link).unwrap();
+        plan.execute(&hooks).unwrap();
+        assert!(dir.join("target").is_symlink());
+        assert!(hook_log(&dir).is_empty());
+    }
+}
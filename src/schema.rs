@@ -0,0 +1,164 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A string, number, or boolean. Numbers and booleans are used as they are written
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Scalar {
+    String(String),
+    Number(f64),
+    Bool(bool),
+}
+
+/// A variable's value, either a scalar or a list that fans out into one link per element
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum VariableValue {
+    Scalar(Scalar),
+    List(Vec<Scalar>),
+}
+
+/// Variables as 'KEY=VALUE' strings, or a mapping of names to values
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Variable {
+    Definition(String),
+    Mapping(BTreeMap<String, VariableValue>),
+}
+
+/// A command, or a list of commands run in order
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Commands {
+    Command(String),
+    List(Vec<String>),
+}
+
+/// Commands to run when any of the links under the node are stowed or unstowed
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    pre_stow: Option<Commands>,
+    post_stow: Option<Commands>,
+    pre_unstow: Option<Commands>,
+    post_unstow: Option<Commands>,
+}
+
+/// A source to link, relative to the directories it is in
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    src: String,
+    /// Where to link the source. Parent directories are created if they don't exist
+    links: Vec<String>,
+    /// Tags for selecting the entry with '--tag' and '--skip-tag'
+    tags: Option<Vec<String>>,
+    /// Whether the source is age encrypted, and has to be decrypted before it is linked
+    encrypted: Option<bool>,
+}
+
+/// A directory's contents, along with options for everything in it
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct NodeOptions {
+    stow: Stow,
+    /// Variables only visible inside the directory
+    vars: Option<Vec<Variable>>,
+    hooks: Option<Hooks>,
+    /// Tags inherited by every entry in the directory
+    tags: Option<Vec<String>>,
+}
+
+/// The contents of a directory of sources
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Node {
+    Options(NodeOptions),
+    Contents(Stow),
+}
+
+/// An item of a 'stow' list
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Stowable {
+    Entry(Entry),
+    /// Directories of sources, by name
+    Directories(BTreeMap<String, Node>),
+}
+
+/// Sources to link, as a list of entries and directories or a mapping of directory names
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Stow {
+    List(Vec<Stowable>),
+    Directories(BTreeMap<String, Node>),
+}
+
+/// A named set of sources that can be selected on the command line
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Package {
+    stow: Option<Stow>,
+    /// Variables only visible inside the package
+    vars: Option<Vec<Variable>>,
+    hooks: Option<Hooks>,
+    /// Tags inherited by every entry in the package
+    tags: Option<Vec<String>>,
+    /// Packages stowed along with this one
+    requires: Option<Vec<String>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stow::stowfile_schema;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn find_stowfiles(dir: &Path, found: &mut Vec<PathBuf>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                find_stowfiles(&path, found);
+            } else if path.file_name().is_some_and(|name| name == "stowfile") {
+                found.push(path);
+            }
+        }
+    }
+
+    fn validator() -> jsonschema::Validator {
+        let schema = serde_json::to_value(stowfile_schema()).unwrap();
+        jsonschema::validator_for(&schema).unwrap()
+    }
+
+    fn parse(text: &str) -> serde_json::Value {
+        serde_yaml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn examples_match_the_schema() {
+        let mut stowfiles = Vec::new();
+        find_stowfiles(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("examples"),
+            &mut stowfiles,
+        );
+        assert!(!stowfiles.is_empty());
+
+        let validator = validator();
+        for stowfile in stowfiles {
+            let stowfile_contents = parse(&fs::read_to_string(&stowfile).unwrap());
+            let errors: Vec<String> = validator
+                .iter_errors(&stowfile_contents)
+                .map(|error| error.to_string())
+                .collect();
+            assert!(errors.is_empty(), "{:?}: {:?}", stowfile, errors);
+        }
+    }
+
+    #[test]
+    fn unknown_entry_keys_are_rejected() {
+        let validator = validator();
+        assert!(validator.is_valid(&parse("stow:\n  - src: a\n    links: [b]\n    tags: [c]\n")));
+        assert!(!validator.is_valid(&parse("stow:\n  - src: a\n    links: [b]\n    tag: [c]\n")));
+    }
+}
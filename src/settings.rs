@@ -4,47 +4,147 @@ use std::path::{Path, PathBuf};
 #[derive(Debug)]
 pub struct LinkSettings {
     dry_run: bool,
+    #[allow(dead_code)] // TODO: back up existing targets when linking
     backup: Option<PathBuf>,
+    // Identity file for decrypting encrypted sources, overriding the stowfile's
+    identity: Option<PathBuf>,
+    allow_hooks: bool,
 }
 impl LinkSettings {
-    pub fn new(dry_run: bool, backup: Option<PathBuf>) -> Self {
-        LinkSettings { dry_run, backup }
+    pub fn new(
+        dry_run: bool,
+        backup: Option<PathBuf>,
+        identity: Option<PathBuf>,
+        allow_hooks: bool,
+    ) -> Self {
+        LinkSettings {
+            dry_run,
+            backup,
+            identity,
+            allow_hooks,
+        }
     }
 
     pub fn dry_run(&self) -> bool {
         self.dry_run
     }
 
+    #[allow(dead_code)]
     pub fn backup(&self) -> &Option<PathBuf> {
         &self.backup
     }
+
+    pub fn identity(&self) -> Option<&Path> {
+        self.identity.as_deref()
+    }
+
+    pub fn allow_hooks(&self) -> bool {
+        self.allow_hooks
+    }
+}
+
+/// Variables defined outside of the stowfile
+#[derive(Debug)]
+pub struct VarSettings {
+    vars: Vec<String>,
+    vars_files: Vec<PathBuf>,
+    allow_commands: bool,
+}
+impl VarSettings {
+    pub fn new(vars: Vec<String>, vars_files: Vec<PathBuf>, allow_commands: bool) -> Self {
+        VarSettings {
+            vars,
+            vars_files,
+            allow_commands,
+        }
+    }
+
+    pub fn vars(&self) -> &[String] {
+        &self.vars
+    }
+
+    pub fn vars_files(&self) -> &[PathBuf] {
+        &self.vars_files
+    }
+
+    pub fn allow_commands(&self) -> bool {
+        self.allow_commands
+    }
+}
+
+/// A stowfile to load, and the directory its sources are relative to
+#[derive(Debug)]
+pub struct StowfileSettings {
+    path: PathBuf,
+    source_dir: PathBuf,
+    // Name of the layer, when the stowfile was given with '--layer'
+    layer: Option<String>,
+}
+impl StowfileSettings {
+    pub fn new(path: PathBuf, source_dir: PathBuf) -> Self {
+        StowfileSettings {
+            path,
+            source_dir,
+            layer: None,
+        }
+    }
+
+    /// A stowfile whose links replace those of earlier layers for the same targets
+    pub fn layer(path: PathBuf, source_dir: PathBuf, name: String) -> Self {
+        StowfileSettings {
+            path,
+            source_dir,
+            layer: Some(name),
+        }
+    }
+
+    pub fn layer_name(&self) -> Option<&str> {
+        self.layer.as_deref()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn source_dir(&self) -> &Path {
+        &self.source_dir
+    }
 }
 
 pub struct Settings {
-    stowfile_path: PathBuf,
+    // Every stowfile to load. There is more than one only when searching for them recursively or
+    // when using layers, where they are in order of precedence
+    stowfiles: Vec<StowfileSettings>,
     current_working_dir: PathBuf,
     filters: StowFilters,
     link_settings: LinkSettings,
+    var_settings: VarSettings,
 }
 impl Settings {
     pub fn new(
-        stowfile_path: PathBuf,
+        stowfiles: Vec<StowfileSettings>,
         current_working_dir: PathBuf,
-        dry_run: bool,
-        backup: Option<PathBuf>,
+        link_settings: LinkSettings,
         filters: StowFilters,
+        var_settings: VarSettings,
     ) -> Self {
-        let link_settings = LinkSettings::new(dry_run, backup);
         Settings {
-            stowfile_path,
+            stowfiles,
             current_working_dir,
             filters,
             link_settings,
+            var_settings,
         }
     }
 
+    pub fn stowfiles(&self) -> &[StowfileSettings] {
+        &self.stowfiles
+    }
+
+    /// The stowfile that commands which edit a stowfile change. Those commands only run with a
+    /// single stowfile
     pub fn stowfile_path(&self) -> &Path {
-        &self.stowfile_path
+        self.stowfiles[0].path()
     }
 
     pub fn current_working_dir(&self) -> &Path {
@@ -58,4 +158,8 @@ impl Settings {
     pub fn link_settings(&self) -> &LinkSettings {
         &self.link_settings
     }
+
+    pub fn var_settings(&self) -> &VarSettings {
+        &self.var_settings
+    }
 }
@@ -1,14 +1,23 @@
 use crate::filter::StowFilters;
-use crate::link::Link;
+use crate::hooks::Hooks;
+use crate::link::{Encryption, Link};
+use crate::location::{Location, SourceText};
+use crate::plan::{Change, Operation, Plan};
+use crate::schema;
 use crate::settings::LinkSettings;
-use crate::settings::Settings;
+use crate::settings::{Settings, VarSettings};
+use crate::vars::{
+    builtin_variables, load_vars_file, stow_dir_of, Definition, Origin, Providers, Variables,
+};
+use crate::version;
 use anyhow::{bail, Result};
-use lazy_static::lazy_static;
-use log::{debug, trace};
-use regex::Regex;
+use log::{debug, error, info, trace};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
+use std::fmt;
 use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone)]
@@ -16,12 +25,6 @@ struct SmartPopPathBuf {
     pretend_path: Vec<String>,
 }
 impl SmartPopPathBuf {
-    pub fn new() -> Self {
-        SmartPopPathBuf {
-            pretend_path: Vec::new(),
-        }
-    }
-
     pub fn from_path(p: &Path) -> Self {
         SmartPopPathBuf {
             pretend_path: vec![p.to_str().unwrap().to_string()],
@@ -36,26 +39,156 @@ impl SmartPopPathBuf {
         self.pretend_path.pop();
     }
 
-    pub fn to_path_buf(&self) -> PathBuf {
-        let string_repr = self.pretend_path.join("/");
-        PathBuf::from(string_repr)
+    /// The directory the path starts from
+    pub fn root(&self) -> &str {
+        &self.pretend_path[0]
+    }
+
+    /// The parts of the path pushed after the root, which come from the stowfile
+    pub fn relative(&self) -> String {
+        self.pretend_path[1..].join("/")
+    }
+}
+impl fmt::Display for SmartPopPathBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.pretend_path.join("/"))
+    }
+}
+
+/// Keys of a directory node's options
+const NODE_STOW_KEY: &str = "stow";
+const NODE_VARS_KEY: &str = "vars";
+const NODE_HOOKS_KEY: &str = "hooks";
+/// Both link entries and directory nodes may have tags
+const TAGS_KEY: &str = "tags";
+/// Keys of a link entry. Link entries may mark their source as age encrypted
+const SRC_KEY: &str = "src";
+const LINKS_KEY: &str = "links";
+const ENCRYPTED_KEY: &str = "encrypted";
+const ENTRY_KEYS: &[&str] = &[SRC_KEY, LINKS_KEY, TAGS_KEY, ENCRYPTED_KEY];
+const NODE_KEYS: &[&str] = &[NODE_STOW_KEY, NODE_VARS_KEY, NODE_HOOKS_KEY, TAGS_KEY];
+/// Packages take the same options as directory nodes, plus the packages they require
+const PACKAGE_REQUIRES_KEY: &str = "requires";
+const PACKAGE_KEYS: &[&str] = &[
+    NODE_STOW_KEY,
+    NODE_VARS_KEY,
+    NODE_HOOKS_KEY,
+    TAGS_KEY,
+    PACKAGE_REQUIRES_KEY,
+];
+
+fn check_option_keys(options: &serde_yaml::Mapping, allowed: &[&str], node: &str) -> Result<()> {
+    for key in options.keys() {
+        match key.as_str() {
+            Some(key) if allowed.contains(&key) => {}
+            Some(key) => bail!(
+                "Malformatted stowfile. Unknown option '{}' for '{}'",
+                key,
+                node
+            ),
+            None => bail!("Malformatted stowfile"),
+        }
     }
+    Ok(())
+}
+
+fn parse_string_list(list: &serde_yaml::Value, key: &str) -> Result<Vec<String>> {
+    let Some(list) = list.as_sequence() else {
+        bail!("Malformatted stowfile. '{}' must be a list", key);
+    };
+    list.iter()
+        .map(|item| match item.as_str() {
+            Some(item) => Ok(item.to_string()),
+            None => bail!(
+                "Malformatted stowfile. {:?} in '{}' is not a string",
+                item,
+                key
+            ),
+        })
+        .collect()
+}
+
+fn parse_tags(tags: &serde_yaml::Value) -> Result<Vec<String>> {
+    parse_string_list(tags, TAGS_KEY)
+}
+
+/// What a node in the stow tree passes down to its children
+#[derive(Debug, Clone)]
+struct Scope {
+    variables: Variables,
+    tags: Vec<String>,
+    package: Option<String>,
+    // Indices of the hooks of this node and the nodes it is in
+    hooks: Vec<usize>,
+}
 
-    pub fn to_string(&self) -> String {
-        self.pretend_path.join("/")
+/// Select the named packages, along with every package they require when `with_requirements`
+fn select_packages(
+    requested: &[String],
+    requirements: &HashMap<String, Vec<String>>,
+    with_requirements: bool,
+) -> Result<HashSet<String>> {
+    let mut selected = HashSet::new();
+    let mut to_visit: Vec<String> = requested.to_vec();
+    while let Some(name) = to_visit.pop() {
+        let Some(requires) = requirements.get(&name) else {
+            bail!("Package '{}' is not in the stowfile", name);
+        };
+        if selected.insert(name.clone()) {
+            debug!("Selected package '{}'", name);
+            if with_requirements {
+                to_visit.extend(requires.iter().cloned());
+            }
+        }
     }
+    Ok(selected)
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+/// A stowfile for nstow, the symlink farm manager
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug)]
+#[schemars(title = "Stowfile")]
 struct StowfileContents {
-    vars: Option<Vec<String>>,
+    /// Version of the stowfile format
+    version: Option<u64>,
+    /// The oldest nstow that can read the stowfile
+    #[schemars(with = "Option<schema::Scalar>")]
+    min_nstow_version: Option<serde_yaml::Value>,
+    /// Variables for use in sources and links
+    #[schemars(with = "Option<Vec<schema::Variable>>")]
+    vars: Option<Vec<serde_yaml::Value>>,
+    /// A file of variables, relative to the stowfile. It is skipped if it doesn't exist
+    vars_file: Option<String>,
+    /// Allow variables to run commands with '${cmd:...}'
+    allow_commands: Option<bool>,
+    /// Age identity file for decrypting encrypted sources
+    age_identity: Option<String>,
+    #[schemars(with = "Option<schema::Hooks>")]
+    hooks: Option<serde_yaml::Value>,
+    #[serde(default)]
+    #[schemars(with = "Option<schema::Stow>")]
     stow: serde_yaml::Value,
+    /// Named sets of sources that can be selected on the command line
+    #[schemars(with = "Option<BTreeMap<String, schema::Package>>")]
+    packages: Option<serde_yaml::Mapping>,
+}
+
+/// JSON Schema for stowfiles, generated from the types stowfiles are read into
+pub fn stowfile_schema() -> schemars::Schema {
+    schemars::schema_for!(StowfileContents)
 }
 
 #[derive(Debug)]
 struct Stowfile<'a> {
     stows: serde_yaml::Value,
-    variables: HashMap<String, String>,
+    packages: serde_yaml::Mapping,
+    hooks: Option<serde_yaml::Value>,
+    variables: Variables,
+    stow_dir: PathBuf,
+    // Age identity file for encrypted sources
+    identity: Option<PathBuf>,
+    source: RefCell<SourceText>,
+    // Every 'hooks' section found while traversing the stow tree
+    collected_hooks: RefCell<Vec<Hooks<'a>>>,
     filters: &'a StowFilters,
     link_settings: &'a LinkSettings,
 }
@@ -65,83 +198,189 @@ impl<'a> Stowfile<'a> {
         stowfile_path: &'a Path,
         filters: &'a StowFilters,
         link_settings: &'a LinkSettings,
+        var_settings: &'a VarSettings,
     ) -> Result<Self> {
-        let f = std::fs::File::open(stowfile_path)?;
-        let contents: StowfileContents = serde_yaml::from_reader(f)?;
+        let text = std::fs::read_to_string(stowfile_path)?;
+        // Check the version before the contents, which may not be understood by this nstow
+        let contents: serde_yaml::Value = serde_yaml::from_str(&text)?;
+        version::check_stowfile(&contents, stowfile_path)?;
+        let contents: StowfileContents = serde_yaml::from_value(contents)?;
+        let mut source = SourceText::new(stowfile_path, &text);
+        let stow_dir = stow_dir_of(stowfile_path)?;
 
-        // Save env vars for evaluation later
-        let mut variables = HashMap::new();
-        for (key, value) in env::vars() {
-            variables.insert(key, value);
-        }
+        // Env vars and built-ins are used as-is, without expanding any variables in their values.
+        // Built-ins take precedence over the environment, and the stowfile's variables override both
+        let mut base_variables: HashMap<String, String> = env::vars().collect();
+        base_variables.extend(builtin_variables(&stow_dir));
+
+        // Running commands for variable values has to be opted into
+        let allow_commands =
+            var_settings.allow_commands() || contents.allow_commands.unwrap_or(false);
+        let providers = Providers::new(&stow_dir, allow_commands);
 
         // If the stowfile contains variable definitions, add them to our collection
+        let mut definitions = Vec::new();
         if let Some(user_defined_variables) = &contents.vars {
             for var in user_defined_variables {
-                let Ok((key, value)) = parse_variable(&var) else {
-                    bail!("Malformatted stowfile variable '{}'", var)
-                };
-                variables.insert(key, value);
+                definitions.append(&mut Definition::from_yaml(var, &mut source)?);
+            }
+        }
+
+        // The stowfile's vars file is optional, so it can point at an untracked per-machine file.
+        // Its path may use any variable defined so far
+        if let Some(vars_file) = &contents.vars_file {
+            let vars_file =
+                Variables::resolve(base_variables.clone(), &definitions, providers.clone())?
+                    .expand(vars_file)?;
+            let vars_file = stow_dir.join(vars_file);
+            if vars_file.exists() {
+                definitions.append(&mut load_vars_file(&vars_file)?);
+            } else {
+                info!("Vars file {:?} does not exist. Skipping", vars_file);
             }
         }
+
+        // Variables from the command line take precedence over everything in the stowfile
+        for vars_file in var_settings.vars_files() {
+            definitions.append(&mut load_vars_file(vars_file)?);
+        }
+        for var in var_settings.vars() {
+            definitions.push(Definition::parse(var, Origin::CommandLine)?);
+        }
+
+        // Expand every variable once, up front, so links only need to substitute final values
+        let variables = Variables::resolve(base_variables, &definitions, providers)?;
+
+        // An identity from the command line takes precedence over the stowfile's
+        let identity = match (link_settings.identity(), &contents.age_identity) {
+            (Some(identity), _) => Some(identity.to_path_buf()),
+            (None, Some(identity)) => Some(stow_dir.join(variables.expand(identity)?)),
+            (None, None) => None,
+        };
+
+        if contents.stow.is_null() && contents.packages.is_none() {
+            bail!("Malformatted stowfile. Expected a 'stow' or 'packages' section");
+        }
+
         Ok(Stowfile {
             stows: contents.stow,
+            packages: contents.packages.unwrap_or_default(),
+            hooks: contents.hooks,
             variables,
+            stow_dir,
+            identity,
+            source: RefCell::new(source),
+            collected_hooks: RefCell::new(Vec::new()),
             filters,
             link_settings,
         })
     }
 
     fn traverse_sequence(
-        &mut self,
+        &self,
         stowables: &serde_yaml::Sequence,
         current_src_path: &mut SmartPopPathBuf,
+        scope: &Scope,
     ) -> Result<Vec<Link<'a>>> {
         let mut collected_links = Vec::new();
         for stowable in stowables {
             if stowable.is_mapping() {
                 let stowable = stowable.as_mapping().unwrap();
 
-                if stowable.contains_key("src") {
+                if stowable.contains_key(SRC_KEY) {
                     // Found a link to make
-                    let src = &stowable["src"];
+                    let src = &stowable[SRC_KEY];
                     if !src.is_string() {
                         bail!("Malformatted stowfile");
                     }
                     let src = src.as_str().unwrap();
-                    current_src_path.push(src);
+                    check_option_keys(stowable, ENTRY_KEYS, src)?;
 
-                    let links = &stowable["links"];
+                    let links = &stowable[LINKS_KEY];
                     if !links.is_sequence() {
                         bail!("Malformatted stowfile");
                     }
                     let targets = links.as_sequence().unwrap();
 
-                    for target in targets {
+                    // Every target is located, even when the entry is filtered out, so later
+                    // lookups of the same text find the right lines
+                    let locations: Vec<Option<Location>> = {
+                        let mut source = self.source.borrow_mut();
+                        targets
+                            .iter()
+                            .map(|target| target.as_str().map(|target| source.locate(target)))
+                            .collect()
+                    };
+
+                    // Entries inherit the tags of the nodes they are in
+                    let mut tags = scope.tags.clone();
+                    if let Some(entry_tags) = stowable.get(TAGS_KEY) {
+                        tags.extend(parse_tags(entry_tags)?);
+                    }
+
+                    // Only the text from the stowfile is expanded, since the stow dir may
+                    // contain a '$' of its own
+                    current_src_path.push(src);
+                    let processed_src = scope.variables.expand(&current_src_path.relative());
+                    current_src_path.pop();
+                    let processed_src = format!("{}/{}", current_src_path.root(), processed_src?);
+                    if !self.filters.check_src(&processed_src) {
+                        continue;
+                    }
+
+                    let encrypted = match stowable.get(ENCRYPTED_KEY) {
+                        Some(encrypted) => match encrypted.as_bool() {
+                            Some(encrypted) => encrypted,
+                            None => bail!(
+                                "Malformatted stowfile. '{}' of {:?} must be true or false",
+                                ENCRYPTED_KEY,
+                                processed_src
+                            ),
+                        },
+                        None => false,
+                    };
+
+                    for (target, location) in targets.iter().zip(locations) {
                         if !target.is_string() {
                             bail!("Malformatted stowfile");
                         }
 
-                        let processed_src =
-                            var_replacement(&current_src_path.to_string(), &mut self.variables)?;
-                        if !self.filters.check_src(&processed_src) {
-                            continue;
-                        }
+                        // A target that references a list variable fans out into one link per
+                        // element of the list
+                        let processed_targets =
+                            scope.variables.expand_all(target.as_str().unwrap())?;
+                        for processed_target in processed_targets {
+                            if !self.filters.check_target(&processed_target) {
+                                continue;
+                            }
 
-                        let processed_target =
-                            var_replacement(target.as_str().unwrap(), &mut self.variables)?;
-                        if !self.filters.check_target(&processed_target) {
-                            continue;
+                            // Continue and save the link only if it passes the filters
+                            let encryption = if encrypted {
+                                Some(Encryption::new(
+                                    Path::new(&processed_src),
+                                    self.identity.clone(),
+                                )?)
+                            } else {
+                                None
+                            };
+                            let mut link = Link::new(
+                                processed_src.clone(),
+                                processed_target,
+                                tags.clone(),
+                                scope.package.clone(),
+                                scope.hooks.clone(),
+                                encryption,
+                                self.link_settings,
+                            )?;
+                            if let Some(location) = &location {
+                                link.set_location(location.clone());
+                            }
+                            collected_links.push(link);
                         }
-
-                        // Continue and save the link only if it passes the filters
-                        let link = Link::new(processed_src, processed_target, &self.link_settings)?;
-                        collected_links.push(link);
-                        current_src_path.pop();
                     }
                 } else {
                     // Just another mapping
-                    let mut new_links = self.traverse_mapping(stowable, current_src_path)?;
+                    let mut new_links = self.traverse_mapping(stowable, current_src_path, scope)?;
                     collected_links.append(&mut new_links);
                 }
             } else {
@@ -152,33 +391,183 @@ impl<'a> Stowfile<'a> {
     }
 
     fn traverse_value(
-        &mut self,
+        &self,
         current_value: &serde_yaml::Value,
         current_src_path: &mut SmartPopPathBuf,
+        scope: &Scope,
     ) -> Result<Vec<Link<'a>>> {
         trace!("Current serde_yaml::Value: {:?}", current_value);
         let collected_links = if current_value.is_mapping() {
-            self.traverse_mapping(current_value.as_mapping().unwrap(), current_src_path)?
+            self.traverse_mapping(current_value.as_mapping().unwrap(), current_src_path, scope)?
         } else if current_value.is_sequence() {
-            self.traverse_sequence(current_value.as_sequence().unwrap(), current_src_path)?
+            self.traverse_sequence(
+                current_value.as_sequence().unwrap(),
+                current_src_path,
+                scope,
+            )?
         } else {
             bail!("Malformatted stowfile");
         };
         Ok(collected_links)
     }
 
-    pub fn get_links(&mut self, mut current_src_path: SmartPopPathBuf) -> Result<Vec<Link<'a>>> {
-        let stows = self.stows.clone(); // TODO: instead of dealing with the borrow-checker I just
-                                        // cloned. Not a huge performace hit, but still should be
-                                        // fixed later
-        let links = self.traverse_value(&stows, &mut current_src_path)?;
+    pub fn get_links(&self, mut current_src_path: SmartPopPathBuf) -> Result<Vec<Link<'a>>> {
+        let mut scope = Scope {
+            variables: self.variables.clone(),
+            tags: Vec::new(),
+            package: None,
+            hooks: Vec::new(),
+        };
+        if let Some(hooks) = &self.hooks {
+            scope
+                .hooks
+                .push(self.add_hooks(hooks, "stowfile", &scope.variables)?);
+        }
+        let mut links = if self.stows.is_null() {
+            Vec::new()
+        } else {
+            self.traverse_value(&self.stows, &mut current_src_path, &scope)?
+        };
+
+        // Package sources are relative to the stowfile, just like the top level 'stow' section
+        for (name, package) in &self.packages {
+            let Some(name) = name.as_str() else {
+                bail!("Malformatted stowfile. Package names must be strings");
+            };
+            let Some(options) = package.as_mapping() else {
+                bail!(
+                    "Malformatted stowfile. Package '{}' must be a mapping",
+                    name
+                );
+            };
+            check_option_keys(options, PACKAGE_KEYS, name)?;
+            let mut package_scope = self.options_scope(options, &scope, name)?;
+            package_scope.package = Some(name.to_string());
+            let stows = options
+                .get(NODE_STOW_KEY)
+                .unwrap_or(&serde_yaml::Value::Null);
+            if !stows.is_null() {
+                links.append(&mut self.traverse_value(
+                    stows,
+                    &mut current_src_path,
+                    &package_scope,
+                )?);
+            }
+        }
         Ok(links)
     }
 
+    /// Names of the packages that each package requires
+    pub fn package_requirements(&self) -> Result<HashMap<String, Vec<String>>> {
+        let mut requirements = HashMap::new();
+        for (name, package) in &self.packages {
+            let Some(name) = name.as_str() else {
+                bail!("Malformatted stowfile. Package names must be strings");
+            };
+            let requires = match package.get(PACKAGE_REQUIRES_KEY) {
+                Some(requires) => parse_string_list(requires, PACKAGE_REQUIRES_KEY)?,
+                None => Vec::new(),
+            };
+            requirements.insert(name.to_string(), requires);
+        }
+        for (name, requires) in &requirements {
+            if let Some(missing) = requires.iter().find(|r| !requirements.contains_key(*r)) {
+                bail!(
+                    "Package '{}' requires package '{}', which is not in the stowfile",
+                    name,
+                    missing
+                );
+            }
+        }
+        Ok(requirements)
+    }
+
+    /// Variables declared by a node in the stow tree. They are visible to the node's children and
+    /// shadow any variables of the same name from outer scopes
+    fn scoped_variables(
+        &self,
+        node_vars: &serde_yaml::Value,
+        variables: &Variables,
+    ) -> Result<Variables> {
+        let Some(node_vars) = node_vars.as_sequence() else {
+            bail!("Malformatted stowfile. A node's 'vars' must be a list");
+        };
+        let mut definitions = Vec::new();
+        for var in node_vars {
+            let mut source = self.source.borrow_mut();
+            definitions.append(&mut Definition::from_yaml(var, &mut source)?);
+        }
+        variables.with_definitions(&definitions)
+    }
+
+    /// Keep track of a 'hooks' section, returning its index
+    fn add_hooks(
+        &self,
+        hooks: &serde_yaml::Value,
+        node: &str,
+        variables: &Variables,
+    ) -> Result<usize> {
+        let hooks = Hooks::from_yaml(
+            hooks,
+            node,
+            variables.environment(),
+            &self.stow_dir,
+            self.link_settings,
+        )?;
+        let mut collected_hooks = self.collected_hooks.borrow_mut();
+        collected_hooks.push(hooks);
+        Ok(collected_hooks.len() - 1)
+    }
+
+    /// Every 'hooks' section in the stowfile, indexed by the links' hook indices
+    pub fn into_hooks(self) -> Vec<Hooks<'a>> {
+        self.collected_hooks.into_inner()
+    }
+
+    /// Apply a node's vars, tags, and hooks to the scope of its parent
+    fn options_scope(
+        &self,
+        options: &serde_yaml::Mapping,
+        scope: &Scope,
+        node: &str,
+    ) -> Result<Scope> {
+        let mut node_scope = scope.clone();
+        if let Some(node_vars) = options.get(NODE_VARS_KEY) {
+            node_scope.variables = self.scoped_variables(node_vars, &scope.variables)?;
+        }
+        if let Some(node_tags) = options.get(TAGS_KEY) {
+            node_scope.tags.extend(parse_tags(node_tags)?);
+        }
+        // Hooks see the node's own variables
+        if let Some(node_hooks) = options.get(NODE_HOOKS_KEY) {
+            let index = self.add_hooks(node_hooks, node, &node_scope.variables)?;
+            node_scope.hooks.push(index);
+        }
+        Ok(node_scope)
+    }
+
+    /// A directory node is either a list/mapping of its contents, or a mapping of options with its
+    /// contents under the 'stow' key
+    fn traverse_node(
+        &self,
+        node: &serde_yaml::Value,
+        current_src_path: &mut SmartPopPathBuf,
+        scope: &Scope,
+    ) -> Result<Vec<Link<'a>>> {
+        let Some(options) = node.as_mapping().filter(|m| m.contains_key(NODE_STOW_KEY)) else {
+            return self.traverse_value(node, current_src_path, scope);
+        };
+        let node = current_src_path.to_string();
+        check_option_keys(options, NODE_KEYS, &node)?;
+        let node_scope = self.options_scope(options, scope, &node)?;
+        self.traverse_value(&options[NODE_STOW_KEY], current_src_path, &node_scope)
+    }
+
     fn traverse_mapping(
-        &mut self,
+        &self,
         current_node: &serde_yaml::Mapping,
         current_src_path: &mut SmartPopPathBuf,
+        scope: &Scope,
     ) -> Result<Vec<Link<'a>>> {
         trace!("Current Mapping: {:?}", current_node);
         let mut collected_links = Vec::new();
@@ -190,9 +579,9 @@ impl<'a> Stowfile<'a> {
                 }
                 current_src_path.push(name.as_str().unwrap());
 
-                let mut new_links = self.traverse_value(child.1, current_src_path)?;
-                collected_links.append(&mut new_links);
+                let new_links = self.traverse_node(child.1, current_src_path, scope);
                 current_src_path.pop();
+                collected_links.append(&mut new_links?);
             } else {
                 bail!("Malformatted stowfile");
             }
@@ -201,113 +590,463 @@ impl<'a> Stowfile<'a> {
     }
 }
 
-fn parse_variable(var: &str) -> Result<(String, String)> {
-    // TODO: could all this be simplified with Nom instead?
-    trace!("Parsing stowfile variable '{}'", var);
-    let parts: Vec<&str> = var.split("=").collect();
-    let (key, value) = match parts.len() {
-        0 | 1 => {
-            bail!("Could not parse variable");
-        }
-        2 => {
-            let key = parts[0];
-            let value = parts[1];
-            (key.to_string(), value.to_string())
+/// Report links that clash: two links to the same target, or a link whose target is inside
+/// another link's target. Returns the number of clashes found
+fn report_clashes(links: &[&Link]) -> usize {
+    let mut clashes = 0;
+    let mut targets: HashMap<&Path, &Link> = HashMap::new();
+    for link in links {
+        // The same link may be selected by more than one operation
+        match targets.insert(link.target(), link) {
+            Some(other) if !std::ptr::eq(other, *link) => {
+                error!(
+                    "Target {:?} is linked by both {} and {}",
+                    link.target(),
+                    other.describe_origin(),
+                    link.describe_origin()
+                );
+                clashes += 1;
+            }
+            _ => {}
         }
-        _ => {
-            let key = parts[0];
-            // Combine the rest of parts into the value. We wont juge if a '=' should or shouldn't
-            // be in the variable's value
-            let value = parts[1..].join("=");
-            (key.to_string(), value)
+    }
+    for link in links {
+        for parent in link.target().ancestors().skip(1) {
+            if let Some(other) = targets.get(parent) {
+                error!(
+                    "Target {:?} of {} is inside {:?}, the target of {}",
+                    link.target(),
+                    link.describe_origin(),
+                    parent,
+                    other.describe_origin()
+                );
+                clashes += 1;
+            }
         }
-    };
-
-    Ok((key, value))
+    }
+    clashes
 }
 
-fn var_replacement(text: &str, known_variables: &mut HashMap<String, String>) -> Result<String> {
-    // Compile regex once with lazy static
-    lazy_static! {
-        // Regex matches shell-style variables '${VAR_NAME}' that contans any alphanumeric char or '_'
-        // Var name is first capture group
-        // TODO: invalid variable syntax should be caught. Like having a '-' in the var name
-        static ref RE: Regex = Regex::new(r"\$\{([[:alpha:]_[0-9]]+)\}").unwrap();
-    }
-    let mut processed_text = text.to_string();
-    for cap in RE.captures_iter(&text) {
-        let full_match = &cap[0];
-        let key_to_replace = &cap[1];
-        trace!(
-            "Found key of another variable '{}' in stowfile defined variable '{}'",
-            key_to_replace,
-            text
-        );
-
-        let Some(replacement_value) = known_variables.get(key_to_replace) else {
-            bail!("Undefined variable '{}' found while processing '{}'", key_to_replace, text);
-        };
-
-        // Perform recursive variable replacement in our value
-        let replacement_value = var_replacement(&replacement_value.clone(), known_variables)?;
-
-        // Add this key/value pair back to our variable map
-        known_variables.insert(key_to_replace.to_string(), replacement_value.clone());
-
-        processed_text = processed_text.replace(full_match, &replacement_value);
-        debug!("Replaced {:?} with {:?}", full_match, replacement_value);
+/// Check that links which are stowed together don't clash. Every clash is reported before failing
+fn check_targets<'l, 'a: 'l>(groups: impl IntoIterator<Item = Vec<&'l Link<'a>>>) -> Result<()> {
+    let clashes: usize = groups.into_iter().map(|links| report_clashes(&links)).sum();
+    if clashes > 0 {
+        bail!("Found {} clashing target(s). Nothing was changed", clashes);
     }
-    Ok(processed_text)
+    Ok(())
 }
 
 pub struct Stow<'a> {
     links: Vec<Link<'a>>,
-    settings: &'a Settings,
+    // Links excluded by tags, along with the reason they were excluded
+    skipped_links: Vec<(Link<'a>, String)>,
+    // Names of the packages that each package requires
+    requirements: HashMap<String, Vec<String>>,
+    hooks: Vec<Hooks<'a>>,
 }
 impl<'a> Stow<'a> {
     pub fn with_settings(settings: &'a Settings) -> Result<Self> {
-        let mut stowfile = Stowfile::new(
-            settings.stowfile_path(),
-            settings.filters(),
-            settings.link_settings(),
-        )?;
-        let links =
-            stowfile.get_links(SmartPopPathBuf::from_path(settings.current_working_dir()))?;
-        Ok(Stow { links, settings })
-    }
+        let mut all_links = Vec::new();
+        let mut requirements: HashMap<String, Vec<String>> = HashMap::new();
+        let mut hooks = Vec::new();
+        // Index of the layer each target came from, so later layers can replace earlier links
+        let mut layered: HashMap<PathBuf, usize> = HashMap::new();
+        for (index, stowfile_settings) in settings.stowfiles().iter().enumerate() {
+            debug!("Loading stowfile {:?}", stowfile_settings.path());
+            let stowfile = Stowfile::new(
+                stowfile_settings.path(),
+                settings.filters(),
+                settings.link_settings(),
+                settings.var_settings(),
+            )?;
+            let mut links =
+                stowfile.get_links(SmartPopPathBuf::from_path(stowfile_settings.source_dir()))?;
+            for (name, requires) in stowfile.package_requirements()? {
+                requirements.entry(name).or_default().extend(requires);
+            }
+            for link in &mut links {
+                link.offset_hooks(hooks.len());
+            }
+            hooks.extend(stowfile.into_hooks());
 
-    pub fn stow(&self) -> Result<()> {
-        trace!("Iterating over links for stowing: {:#?}", &self.links);
-        for link in &self.links {
-            link.link()?;
+            if let Some(layer) = stowfile_settings.layer_name() {
+                for link in &mut links {
+                    link.set_layer(layer);
+                    let earlier = layered
+                        .insert(link.target().to_path_buf(), index)
+                        .filter(|earlier| *earlier != index);
+                    let Some(earlier) = earlier else {
+                        continue;
+                    };
+                    let earlier_layer = settings.stowfiles()[earlier]
+                        .layer_name()
+                        .unwrap_or_default();
+                    debug!(
+                        "Layer '{}' replaces the link to {:?} from layer '{}'",
+                        layer,
+                        link.target(),
+                        earlier_layer
+                    );
+                    link.replace_layer(earlier_layer);
+                    all_links.retain(|earlier: &Link| earlier.target() != link.target());
+                }
+            }
+            all_links.append(&mut links);
         }
-        Ok(())
-    }
 
-    pub fn unstow(&self) -> Result<()> {
-        trace!("Iterating over links for unstowing: {:#?}", &self.links);
-        for link in &self.links {
-            link.unlink()?;
+        let mut links = Vec::new();
+        let mut skipped_links = Vec::new();
+        for link in all_links {
+            match settings.filters().tag_exclusion_reason(link.tags()) {
+                Some(reason) => {
+                    debug!("Skipping {:?}: {}", link, reason);
+                    skipped_links.push((link, reason));
+                }
+                None => links.push(link),
+            }
         }
-        Ok(())
+        // Links in the same package, or outside of any package, are always stowed together.
+        // Links in different packages only clash when those packages are selected together
+        let mut packages: BTreeMap<Option<&str>, Vec<&Link>> = BTreeMap::new();
+        for link in &links {
+            packages.entry(link.package()).or_default().push(link);
+        }
+        check_targets(packages.into_values())?;
+
+        Ok(Stow {
+            links,
+            skipped_links,
+            requirements,
+            hooks,
+        })
     }
 
-    pub fn restow(&self) -> Result<()> {
-        trace!("Iterating over links for restowing: {:#?}", &self.links);
-        for link in &self.links {
-            // TODO: Ignore any unlinking errors if linking will cover up the issue
-            link.unlink()?;
-            link.link()?;
-            // TODO: when running with --dry-run do not let link() write a warining when a link exists. unlink() would have taken care of that
+    /// Check if a link is in the requested packages, or the packages they require when
+    /// `with_requirements`. Entries outside of a package are only used when no packages are
+    /// requested
+    fn package_filter(
+        &self,
+        packages: &[String],
+        with_requirements: bool,
+    ) -> Result<impl Fn(&Link) -> bool> {
+        let selected = if packages.is_empty() {
+            None
+        } else {
+            Some(select_packages(
+                packages,
+                &self.requirements,
+                with_requirements,
+            )?)
+        };
+        Ok(move |link: &Link| match &selected {
+            None => true,
+            Some(selected) => link
+                .package()
+                .map(|package| selected.contains(package))
+                .unwrap_or(false),
+        })
+    }
+
+    /// Stow, unstow, and restow as one plan, in the order the operations were given
+    pub fn apply(&self, operations: &[Operation]) -> Result<()> {
+        let mut plan = Plan::new();
+        let mut stowed = Vec::new();
+        for operation in operations {
+            // Requirements are stowed along with a package, but unstowing a package leaves the
+            // packages it requires alone, since other packages may still need them
+            let unstow = operation.change() == Change::Unstow;
+            let selected = self.package_filter(operation.packages(), !unstow)?;
+            let links: Vec<&Link> = self.links.iter().filter(|link| selected(link)).collect();
+            if !unstow {
+                stowed.extend(links.iter().copied());
+            }
+            trace!("Planning {:?}", operation);
+            for link in links {
+                match operation.change() {
+                    Change::Stow => plan.link(link)?,
+                    Change::Unstow => plan.unlink(link, true)?,
+                    Change::Restow => {
+                        plan.unlink(link, false)?;
+                        plan.link(link)?;
+                    }
+                }
+            }
         }
-        Ok(())
+        check_targets([stowed])?;
+        // Links skipped by the tags may still be stowed from an earlier run, using a decrypted copy
+        let links: Vec<&Link> = self
+            .links
+            .iter()
+            .chain(self.skipped_links.iter().map(|(link, _)| link))
+            .collect();
+        plan.remove_unused_decrypted(&links)?;
+        plan.execute(&self.hooks)
     }
 
-    pub fn status(&self) -> Result<()> {
+    pub fn status(&self, packages: &[String]) -> Result<()> {
         trace!("Iterating over links for status: {:#?}", &self.links);
-        for link in &self.links {
+        let selected = self.package_filter(packages, true)?;
+        for link in self.links.iter().filter(|link| selected(link)) {
             link.status()?;
         }
+        for (link, reason) in &self.skipped_links {
+            if selected(link) {
+                link.skipped_status(reason);
+            }
+        }
         Ok(())
     }
+
+    /// Links from a source file, including links excluded by tags
+    pub fn links_from<'s>(&'s self, src: &'s Path) -> impl Iterator<Item = &'s Link<'a>> {
+        self.links
+            .iter()
+            .chain(self.skipped_links.iter().map(|(link, _)| link))
+            .filter(move |link| link.src() == src)
+    }
+
+    /// Every entry was already checked while loading the stowfile, so just report what was found
+    pub fn validate(&self) {
+        let count = self.links.len() + self.skipped_links.len();
+        info!("Stowfile is valid ({} links)", count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::StowfileSettings;
+    use crate::testing::scratch_dir;
+    use std::fs;
+
+    /// Write a stowfile in a directory under `root`, along with empty files for its sources.
+    /// 'ROOT' in the stowfile is replaced with `root`
+    fn write_stowfile(root: &Path, name: &str, sources: &[&str], text: &str) -> PathBuf {
+        let dir = root.join(name);
+        fs::create_dir_all(&dir).unwrap();
+        for src in sources {
+            fs::write(dir.join(src), "").unwrap();
+        }
+        let path = dir.join("stowfile");
+        fs::write(&path, text.replace("ROOT", &root.display().to_string())).unwrap();
+        path
+    }
+
+    fn settings(stowfiles: Vec<StowfileSettings>) -> Settings {
+        crate::testing::settings(stowfiles, PathBuf::from("/"), true)
+    }
+
+    fn stowfile_settings(path: &Path, layer: Option<&str>) -> StowfileSettings {
+        let dir = path.parent().unwrap().to_path_buf();
+        match layer {
+            Some(name) => StowfileSettings::layer(path.to_path_buf(), dir, name.to_string()),
+            None => StowfileSettings::new(path.to_path_buf(), dir),
+        }
+    }
+
+    fn load_error(settings: &Settings) -> String {
+        match Stow::with_settings(settings) {
+            Ok(_) => panic!("the stowfile loaded"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    fn stow_packages(stow: &Stow, packages: &[&str]) -> Result<()> {
+        let packages = packages.iter().map(|name| name.to_string()).collect();
+        stow.apply(&[Operation::new(Change::Stow, packages)])
+    }
+
+    const CLASHES: &str = "Found 1 clashing target(s). Nothing was changed";
+
+    const TWO_PACKAGES: &str = "version: 1
+packages:
+  bash:
+    stow:
+      - src: bashrc
+        links:
+          - ROOT/home/.profile
+  zsh:
+    stow:
+      - src: zshrc
+        links:
+          - ROOT/home/.profile
+";
+
+    #[test]
+    fn packages_claiming_one_target_only_clash_when_stowed_together() {
+        let root = scratch_dir("stow-two-packages");
+        let path = write_stowfile(&root, "dotfiles", &["bashrc", "zshrc"], TWO_PACKAGES);
+        let settings = settings(vec![stowfile_settings(&path, None)]);
+        let stow = Stow::with_settings(&settings).unwrap();
+        assert!(stow_packages(&stow, &["bash"]).is_ok());
+        assert!(stow_packages(&stow, &["zsh"]).is_ok());
+        let error = stow_packages(&stow, &["bash", "zsh"]).unwrap_err();
+        assert_eq!(error.to_string(), CLASHES);
+        // Every package is selected when none are named
+        assert!(stow_packages(&stow, &[]).is_err());
+    }
+
+    #[test]
+    fn a_package_claiming_a_target_twice_clashes_when_loaded() {
+        let root = scratch_dir("stow-same-package");
+        let path = write_stowfile(
+            &root,
+            "dotfiles",
+            &["bashrc", "bash_profile"],
+            "version: 1
+packages:
+  bash:
+    stow:
+      - src: bashrc
+        links:
+          - ROOT/home/.bashrc
+      - src: bash_profile
+        links:
+          - ROOT/home/.bashrc
+",
+        );
+        let settings = settings(vec![stowfile_settings(&path, None)]);
+        assert_eq!(load_error(&settings), CLASHES);
+    }
+
+    #[test]
+    fn selecting_a_link_twice_is_not_a_clash() {
+        let root = scratch_dir("stow-selected-twice");
+        let path = write_stowfile(&root, "dotfiles", &["bashrc", "zshrc"], TWO_PACKAGES);
+        let settings = settings(vec![stowfile_settings(&path, None)]);
+        let stow = Stow::with_settings(&settings).unwrap();
+        let operations = [
+            Operation::new(Change::Stow, vec!["bash".to_string()]),
+            Operation::new(Change::Restow, vec!["bash".to_string()]),
+        ];
+        assert!(stow.apply(&operations).is_ok());
+    }
+
+    #[test]
+    fn a_later_layer_replaces_a_target_but_clashes_with_one_inside_it() {
+        let root = scratch_dir("stow-layers");
+        let base = write_stowfile(
+            &root,
+            "base",
+            &["nvim", "gitconfig"],
+            "version: 1
+stow:
+  - src: nvim
+    links:
+      - ROOT/home/.config/nvim
+  - src: gitconfig
+    links:
+      - ROOT/home/.gitconfig
+",
+        );
+        let replacing = write_stowfile(
+            &root,
+            "replacing",
+            &["gitconfig"],
+            "version: 1
+stow:
+  - src: gitconfig
+    links:
+      - ROOT/home/.gitconfig
+",
+        );
+        let inside = write_stowfile(
+            &root,
+            "inside",
+            &["init.lua"],
+            "version: 1
+stow:
+  - src: init.lua
+    links:
+      - ROOT/home/.config/nvim/init.lua
+",
+        );
+
+        let replaced = settings(vec![
+            stowfile_settings(&base, Some("base")),
+            stowfile_settings(&replacing, Some("replacing")),
+        ]);
+        let stow = Stow::with_settings(&replaced).unwrap();
+        assert!(stow_packages(&stow, &[]).is_ok());
+
+        let clashing = settings(vec![
+            stowfile_settings(&base, Some("base")),
+            stowfile_settings(&inside, Some("inside")),
+        ]);
+        assert_eq!(load_error(&clashing), CLASHES);
+    }
+
+    fn requirements(packages: &[(&str, &[&str])]) -> HashMap<String, Vec<String>> {
+        packages
+            .iter()
+            .map(|(name, requires)| {
+                let requires = requires.iter().map(|r| r.to_string()).collect();
+                (name.to_string(), requires)
+            })
+            .collect()
+    }
+
+    fn select(
+        requested: &[&str],
+        requirements: &HashMap<String, Vec<String>>,
+        with_requirements: bool,
+    ) -> Result<Vec<String>> {
+        let requested: Vec<String> = requested.iter().map(|name| name.to_string()).collect();
+        let mut selected: Vec<String> =
+            select_packages(&requested, requirements, with_requirements)?
+                .into_iter()
+                .collect();
+        selected.sort();
+        Ok(selected)
+    }
+
+    #[test]
+    fn required_packages_are_selected_when_stowing_only() {
+        let requirements = requirements(&[("fonts", &[]), ("sway", &["fonts"]), ("bash", &[])]);
+        assert_eq!(
+            select(&["sway"], &requirements, true).unwrap(),
+            ["fonts", "sway"]
+        );
+        assert_eq!(select(&["sway"], &requirements, false).unwrap(), ["sway"]);
+    }
+
+    #[test]
+    fn unknown_package_is_an_error() {
+        let requirements = requirements(&[("bash", &[])]);
+        let error = select(&["bash", "zsh"], &requirements, true).unwrap_err();
+        assert_eq!(error.to_string(), "Package 'zsh' is not in the stowfile");
+    }
+
+    #[test]
+    fn requires_cycle_selects_each_package_once() {
+        let requirements = requirements(&[("a", &["b"]), ("b", &["c"]), ("c", &["a"])]);
+        assert_eq!(
+            select(&["a"], &requirements, true).unwrap(),
+            ["a", "b", "c"]
+        );
+    }
+
+    #[test]
+    fn requiring_a_missing_package_fails_to_load() {
+        let root = scratch_dir("stow-missing-requirement");
+        let path = write_stowfile(
+            &root,
+            "dotfiles",
+            &["config"],
+            "version: 1
+packages:
+  sway:
+    requires: [fonts]
+    stow:
+      - src: config
+        links:
+          - ROOT/home/.config/sway/config
+",
+        );
+        let settings = settings(vec![stowfile_settings(&path, None)]);
+        assert_eq!(
+            load_error(&settings),
+            "Package 'sway' requires package 'fonts', which is not in the stowfile"
+        );
+    }
 }
@@ -0,0 +1,30 @@
+use crate::filter::{StowFilters, TagFilter};
+use crate::settings::{LinkSettings, Settings, StowfileSettings, VarSettings};
+use std::fs;
+use std::path::PathBuf;
+
+/// An empty directory for a test to write files in, unique to the test. It is cleared at the
+/// start of each run rather than removed afterwards, so a failing test's files can be looked at
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nstow-test-{}", name));
+    if dir.exists() {
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Settings for the stowfiles, without any filters, variables, or hooks from the command line
+pub fn settings(
+    stowfiles: Vec<StowfileSettings>,
+    current_working_dir: PathBuf,
+    dry_run: bool,
+) -> Settings {
+    Settings::new(
+        stowfiles,
+        current_working_dir,
+        LinkSettings::new(dry_run, None, None, false),
+        StowFilters::new(None, None, None, None, None, TagFilter::new(vec![], vec![])),
+        VarSettings::new(Vec::new(), Vec::new(), false),
+    )
+}
@@ -0,0 +1,988 @@
+use crate::location::{Location, SourceText};
+use anyhow::{bail, Context, Result};
+use directories_next::BaseDirs;
+use log::{debug, trace, warn};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::rc::Rc;
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Variable names follow the shell's rules: letters, digits and '_', not starting with a digit
+fn is_valid_name(name: &str) -> bool {
+    name.starts_with(is_name_start) && name.chars().all(is_name_char)
+}
+
+/// Split text into the variable name at its start and whatever follows the name
+fn split_name(text: &str) -> (&str, &str) {
+    let name_len = text.find(|c| !is_name_char(c)).unwrap_or(text.len());
+    text.split_at(name_len)
+}
+
+/// Find the '}' closing a '${', skipping over any nested braces
+fn closing_brace(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+pub fn parse_variable(var: &str) -> Result<(String, String)> {
+    trace!("Parsing stowfile variable '{}'", var);
+    // Split on the first '=' only. We wont juge if a '=' should or shouldn't be in the
+    // variable's value
+    let Some((key, value)) = var.split_once('=') else {
+        bail!("Could not parse variable");
+    };
+    if !is_valid_name(key) {
+        bail!("Invalid variable name '{}'", key);
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// XDG base directory variables and their defaults relative to the home dir, from
+/// https://specifications.freedesktop.org/basedir-spec/latest/
+const XDG_HOME_DIRS: &[(&str, &str)] = &[
+    ("XDG_CONFIG_HOME", ".config"),
+    ("XDG_DATA_HOME", ".local/share"),
+    ("XDG_STATE_HOME", ".local/state"),
+    ("XDG_CACHE_HOME", ".cache"),
+];
+const XDG_SYSTEM_DIRS: &[(&str, &str)] = &[
+    ("XDG_DATA_DIRS", "/usr/local/share/:/usr/share/"),
+    ("XDG_CONFIG_DIRS", "/etc/xdg"),
+];
+
+/// The spec says relative paths are invalid and should be ignored
+fn xdg_env_var(key: &str) -> Option<String> {
+    let value = env::var(key).ok()?;
+    if Path::new(&value).is_absolute() {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+/// One of the XDG_*_HOME directories, from the environment or the spec's default
+pub fn xdg_home_dir(key: &str) -> Option<PathBuf> {
+    let (_, default) = XDG_HOME_DIRS.iter().find(|(k, _)| *k == key)?;
+    match xdg_env_var(key) {
+        Some(value) => Some(PathBuf::from(value)),
+        None => Some(BaseDirs::new()?.home_dir().join(default)),
+    }
+}
+
+/// Split a path into the variable for the deepest XDG or home directory it is in, and the rest of
+/// the path, so it can be written the same way on every machine
+pub fn split_path_variable(path: &Path) -> Option<(&'static str, PathBuf)> {
+    let mut dirs: Vec<(&'static str, PathBuf)> = XDG_HOME_DIRS
+        .iter()
+        .filter_map(|(key, _)| Some((*key, xdg_home_dir(key)?)))
+        .collect();
+    if let Some(base_dirs) = BaseDirs::new() {
+        dirs.push(("HOME", base_dirs.home_dir().to_path_buf()));
+    }
+    dirs.into_iter()
+        .filter_map(|(key, dir)| Some((key, path.strip_prefix(&dir).ok()?.to_path_buf())))
+        .min_by_key(|(_, rest)| rest.components().count())
+}
+
+/// Write a path the way a stowfile would, relative to a variable like '${HOME}' when possible
+pub fn path_text(path: &Path) -> String {
+    match split_path_variable(path) {
+        Some((key, rest)) => format!(
+            "${{{}}}/{}",
+            key,
+            escape_variables(&rest.display().to_string())
+        ),
+        None => escape_variables(&path.display().to_string()),
+    }
+}
+
+/// Variables nstow provides on every machine, so stowfiles do not depend on what the user exports
+pub fn builtin_variables(stow_dir: &Path) -> HashMap<String, String> {
+    let mut builtins = HashMap::new();
+
+    if BaseDirs::new().is_none() {
+        warn!("Unable to find the home directory. XDG variables will not be defined");
+    }
+    for (key, _) in XDG_HOME_DIRS {
+        if let Some(value) = xdg_home_dir(key) {
+            builtins.insert(key.to_string(), value.display().to_string());
+        }
+    }
+    for (key, default) in XDG_SYSTEM_DIRS {
+        let value = env::var(key)
+            .ok()
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| default.to_string());
+        builtins.insert(key.to_string(), value);
+    }
+
+    builtins.insert("STOW_DIR".to_string(), stow_dir.display().to_string());
+    match whoami::fallible::hostname() {
+        Ok(hostname) => {
+            builtins.insert("HOSTNAME".to_string(), hostname);
+        }
+        Err(e) => warn!("Unable to get the hostname: {}", e),
+    }
+    builtins.insert("OS".to_string(), env::consts::OS.to_string());
+    builtins.insert("ARCH".to_string(), env::consts::ARCH.to_string());
+    builtins.insert("USER".to_string(), whoami::username());
+
+    builtins
+}
+
+/// The directory a stowfile lives in, which is also the stow dir its sources are relative to
+pub fn stow_dir_of(stowfile_path: &Path) -> Result<PathBuf> {
+    let stowfile_path = stowfile_path
+        .canonicalize()
+        .with_context(|| format!("Unable to resolve stowfile {:?}", stowfile_path))?;
+    Ok(stowfile_path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default())
+}
+
+/// The value of a variable. Text that references a list expands into one result per element
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    List(Vec<String>),
+}
+impl Value {
+    fn items(&self) -> &[String] {
+        match self {
+            Value::Text(text) => std::slice::from_ref(text),
+            Value::List(items) => items,
+        }
+    }
+
+    /// Like the shell, defaults and required checks treat empty variables the same as unset ones
+    fn is_empty(&self) -> bool {
+        self.items().iter().all(|item| item.is_empty())
+    }
+}
+
+/// Collects the results of expanding text, forking into one result per element of each list
+struct Expansion {
+    results: Vec<String>,
+    is_list: bool,
+}
+impl Expansion {
+    fn new() -> Self {
+        Expansion {
+            results: vec![String::new()],
+            is_list: false,
+        }
+    }
+
+    fn push_str(&mut self, text: &str) {
+        for result in &mut self.results {
+            result.push_str(text);
+        }
+    }
+
+    fn push_value(&mut self, value: &Value) {
+        match value {
+            Value::Text(text) => self.push_str(text),
+            Value::List(items) => {
+                self.is_list = true;
+                self.results = self
+                    .results
+                    .iter()
+                    .flat_map(|result| items.iter().map(move |item| format!("{}{}", result, item)))
+                    .collect();
+            }
+        }
+    }
+
+    fn into_value(mut self) -> Value {
+        if self.is_list {
+            Value::List(self.results)
+        } else {
+            Value::Text(self.results.pop().unwrap_or_default())
+        }
+    }
+}
+
+/// Returns the value of a variable, or None when it is not defined
+type Lookup<'l> = dyn FnMut(&str) -> Result<Option<Value>> + 'l;
+
+/// Values that are only known at runtime, read with '${cmd:COMMAND}' and '${file:PATH}'
+#[derive(Debug, Clone)]
+pub struct Providers {
+    dir: PathBuf,
+    allow_commands: bool,
+    // Each command only runs once, no matter how many times it is referenced
+    command_output: Rc<RefCell<HashMap<String, String>>>,
+}
+impl Providers {
+    /// Commands run in, and relative file paths are relative to, the given dir
+    pub fn new(dir: &Path, allow_commands: bool) -> Self {
+        Providers {
+            dir: dir.to_path_buf(),
+            allow_commands,
+            command_output: Rc::new(RefCell::new(HashMap::new())),
+        }
+    }
+
+    fn run_command(&self, command: &str, text: &str) -> Result<String> {
+        if !self.allow_commands {
+            bail!(
+                "'${{cmd:{}}}' found while processing '{}', but running commands is not allowed. Pass '--allow-commands' or set 'allow_commands: true' in the stowfile",
+                command,
+                text
+            );
+        }
+        if let Some(output) = self.command_output.borrow().get(command) {
+            return Ok(output.clone());
+        }
+
+        debug!("Running command '{}' for variable value", command);
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(&self.dir)
+            .output()
+            .with_context(|| format!("Unable to run command '{}'", command))?;
+        if !output.status.success() {
+            bail!(
+                "Command '{}' failed with {}: {}",
+                command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let stdout = String::from_utf8(output.stdout)
+            .with_context(|| format!("Output of command '{}' is not valid UTF-8", command))?;
+        let stdout = stdout.trim().to_string();
+        self.command_output
+            .borrow_mut()
+            .insert(command.to_string(), stdout.clone());
+        Ok(stdout)
+    }
+
+    fn read_file(&self, path: &str) -> Result<String> {
+        let path = self.dir.join(path);
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Unable to read {:?} for variable value", path))?;
+        Ok(contents.trim().to_string())
+    }
+}
+
+fn lookup_required(key: &str, text: &str, lookup: &mut Lookup) -> Result<Value> {
+    let Some(value) = lookup(key)? else {
+        bail!(
+            "Undefined variable '{}' found while processing '{}'",
+            key,
+            text
+        );
+    };
+    Ok(value)
+}
+
+fn single_value(value: Value, text: &str) -> Result<String> {
+    match value {
+        Value::Text(text) => Ok(text),
+        Value::List(items) => bail!(
+            "'{}' expands to a list of {} values, but only a single value is allowed here",
+            text,
+            items.len()
+        ),
+    }
+}
+
+/// Expand the contents of a '${...}' reference
+fn expand_braced(
+    expression: &str,
+    text: &str,
+    lookup: &mut Lookup,
+    providers: &Providers,
+) -> Result<Value> {
+    let (key, modifier) = split_name(expression);
+    if !is_valid_name(key) {
+        bail!(
+            "Invalid variable name in '${{{}}}' found while processing '{}'",
+            expression,
+            text
+        );
+    }
+
+    if modifier.is_empty() {
+        lookup_required(key, text, lookup)
+    } else if let Some(default) = modifier.strip_prefix(":-") {
+        match lookup(key)? {
+            Some(value) if !value.is_empty() => Ok(value),
+            _ => expand(default, lookup, providers),
+        }
+    } else if let Some(message) = modifier.strip_prefix(":?") {
+        match lookup(key)? {
+            Some(value) if !value.is_empty() => Ok(value),
+            _ if message.is_empty() => {
+                bail!(
+                    "Required variable '{}' is not set (needed by '{}')",
+                    key,
+                    text
+                )
+            }
+            _ => bail!("{}: {}", key, message),
+        }
+    } else if let (Some(argument), "cmd" | "file") = (modifier.strip_prefix(':'), key) {
+        // Providers may use variables in their argument, like '${file:${HOME}/.email}'
+        let argument = single_value(expand(argument, lookup, providers)?, argument)?;
+        let value = if key == "cmd" {
+            providers.run_command(&argument, text)?
+        } else {
+            providers.read_file(&argument)?
+        };
+        Ok(Value::Text(value))
+    } else {
+        bail!(
+            "Invalid variable name in '${{{}}}' found while processing '{}'",
+            expression,
+            text
+        );
+    }
+}
+
+/// Replace shell-style variables in text with their values.
+/// Supports '${VAR}', '$VAR', '${VAR:-default}', '${VAR:?message}', providers like '${cmd:...}'
+/// and a leading '~'.
+/// '$$' is an escape for a literal '$', so '$${VAR}' results in the text '${VAR}'.
+/// Text that references a list variable results in a list, with one element per list element.
+fn expand(text: &str, lookup: &mut Lookup, providers: &Providers) -> Result<Value> {
+    let mut expansion = Expansion::new();
+    let mut rest = text;
+
+    // A leading '~' is the home dir, just like in the shell
+    if rest == "~" || rest.starts_with("~/") {
+        expansion.push_value(&lookup_required("HOME", text, lookup)?);
+        rest = &rest[1..];
+    }
+
+    while let Some(index) = rest.find('$') {
+        expansion.push_str(&rest[..index]);
+        rest = &rest[index + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            expansion.push_str("$");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let Some(end) = closing_brace(after) else {
+                bail!("Unterminated '${{' found while processing '{}'", text);
+            };
+            let expression = &after[..end];
+            let value = expand_braced(expression, text, lookup, providers)?;
+            debug!(
+                "Replaced {:?} with {:?}",
+                format!("${{{}}}", expression),
+                value
+            );
+            expansion.push_value(&value);
+            rest = &after[end + 1..];
+        } else {
+            let (key, after) = split_name(rest);
+            if !is_valid_name(key) {
+                bail!(
+                    "Invalid variable reference after '$' found while processing '{}'. Use '$$' for a literal '$'",
+                    text
+                );
+            }
+            let value = lookup_required(key, text, lookup)?;
+            debug!("Replaced {:?} with {:?}", format!("${}", key), value);
+            expansion.push_value(&value);
+            rest = after;
+        }
+    }
+    expansion.push_str(rest);
+
+    Ok(expansion.into_value())
+}
+
+/// The reverse of `expand` for text without variables, like file names, which are taken literally
+pub fn escape_variables(text: &str) -> String {
+    text.replace('$', "$$")
+}
+
+/// Where a variable's definition came from
+#[derive(Debug, Clone)]
+pub enum Origin {
+    Stowfile(Location),
+    VarsFile(Location),
+    CommandLine,
+}
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::Stowfile(location) | Origin::VarsFile(location) => write!(f, "{}", location),
+            Origin::CommandLine => write!(f, "the command line"),
+        }
+    }
+}
+
+/// A variable whose value may still contain references to other variables
+#[derive(Debug, Clone)]
+pub struct Definition {
+    key: String,
+    value: Value,
+    origin: Origin,
+}
+impl Definition {
+    pub fn new(key: String, value: Value, origin: Origin) -> Self {
+        Definition { key, value, origin }
+    }
+
+    /// Parse a 'KEY=VALUE' string, like the ones in a stowfile's 'vars:' list
+    pub fn parse(var: &str, origin: Origin) -> Result<Self> {
+        let (key, value) = match parse_variable(var) {
+            Ok(variable) => variable,
+            Err(e) => bail!("Malformatted variable '{}' at {}: {}", var, origin, e),
+        };
+        Ok(Definition::new(key, Value::Text(value), origin))
+    }
+
+    /// Parse an entry of a stowfile's 'vars:' list. Entries are either 'KEY=VALUE' strings or
+    /// single entry mappings, which allow lists like 'KEY: [a, b]'
+    pub fn from_yaml(var: &serde_yaml::Value, source: &mut SourceText) -> Result<Vec<Self>> {
+        if let Some(var) = var.as_str() {
+            let key = var.split_once('=').map_or(var, |(key, _)| key);
+            let location = source.locate_definition(key);
+            return Ok(vec![Definition::parse(var, Origin::Stowfile(location))?]);
+        }
+        let Some(mapping) = var.as_mapping() else {
+            bail!("Malformatted stowfile variable {:?}", var);
+        };
+        let mut definitions = Vec::new();
+        for (key, value) in mapping {
+            let Some(key) = key.as_str() else {
+                bail!("Malformatted stowfile variable {:?}", var);
+            };
+            let location = source.locate_definition(key);
+            definitions.push(Definition::from_key_value(
+                key,
+                value,
+                Origin::Stowfile(location),
+            )?);
+        }
+        Ok(definitions)
+    }
+
+    fn from_key_value(key: &str, value: &serde_yaml::Value, origin: Origin) -> Result<Self> {
+        if !is_valid_name(key) {
+            bail!("Invalid variable name '{}' at {}", key, origin);
+        }
+        let value = match value {
+            serde_yaml::Value::Sequence(items) => {
+                let items: Option<Vec<String>> = items.iter().map(yaml_scalar_to_string).collect();
+                let Some(items) = items else {
+                    bail!(
+                        "Malformatted variable '{}' at {}. List elements must be strings",
+                        key,
+                        origin
+                    );
+                };
+                Value::List(items)
+            }
+            value => {
+                let Some(value) = yaml_scalar_to_string(value) else {
+                    bail!(
+                        "Malformatted variable '{}' at {}. Values must be strings or lists",
+                        key,
+                        origin
+                    );
+                };
+                Value::Text(value)
+            }
+        };
+        Ok(Definition::new(key.to_string(), value, origin))
+    }
+}
+
+fn yaml_scalar_to_string(value: &serde_yaml::Value) -> Option<String> {
+    match value {
+        serde_yaml::Value::String(s) => Some(s.clone()),
+        serde_yaml::Value::Number(n) => Some(n.to_string()),
+        serde_yaml::Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// Load variables from a YAML file, either as a mapping of keys to values or as a list of
+/// 'KEY=VALUE' strings like a stowfile's 'vars:'
+fn load_yaml_vars_file(path: &Path, text: &str) -> Result<Vec<Definition>> {
+    let mut source = SourceText::new(path, text);
+    let contents: serde_yaml::Value = serde_yaml::from_str(text)?;
+    let mut definitions = Vec::new();
+    match contents {
+        serde_yaml::Value::Null => {}
+        serde_yaml::Value::Mapping(mapping) => {
+            for (key, value) in &mapping {
+                let Some(key) = key.as_str() else {
+                    bail!("Malformatted vars file {:?}. Keys must be strings", path);
+                };
+                let location = source.locate_definition(key);
+                definitions.push(Definition::from_key_value(
+                    key,
+                    value,
+                    Origin::VarsFile(location),
+                )?);
+            }
+        }
+        serde_yaml::Value::Sequence(sequence) => {
+            for var in &sequence {
+                let Some(var) = var.as_str() else {
+                    bail!(
+                        "Malformatted vars file {:?}. Expected 'KEY=VALUE' strings",
+                        path
+                    );
+                };
+                let key = var.split_once('=').map_or(var, |(key, _)| key);
+                let location = source.locate_definition(key);
+                definitions.push(Definition::parse(var, Origin::VarsFile(location))?);
+            }
+        }
+        _ => bail!("Malformatted vars file {:?}", path),
+    }
+    Ok(definitions)
+}
+
+/// Load variables from a .env file of 'KEY=VALUE' lines. Lines may start with 'export', and
+/// values may be wrapped in quotes
+fn load_env_vars_file(path: &Path, text: &str) -> Result<Vec<Definition>> {
+    let mut definitions = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+        let origin = Origin::VarsFile(Location::new(path, Some(index + 1)));
+        let (key, mut value) = match parse_variable(line) {
+            Ok(variable) => variable,
+            Err(e) => bail!("Malformatted variable '{}' at {}: {}", line, origin, e),
+        };
+        for quote in ['"', '\''] {
+            if value.len() >= 2 && value.starts_with(quote) && value.ends_with(quote) {
+                value = value[1..value.len() - 1].to_string();
+                break;
+            }
+        }
+        definitions.push(Definition::new(key, Value::Text(value), origin));
+    }
+    Ok(definitions)
+}
+
+/// Load variable definitions from a YAML ('.yaml' or '.yml') or .env file
+pub fn load_vars_file(path: &Path) -> Result<Vec<Definition>> {
+    debug!("Loading variables from {:?}", path);
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read vars file {:?}", path))?;
+    let is_yaml = matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("yaml") | Some("yml")
+    );
+    if is_yaml {
+        load_yaml_vars_file(path, &text)
+    } else {
+        load_env_vars_file(path, &text)
+    }
+}
+
+/// Resolves definitions into final values, detecting cycles along the way.
+/// The same variable may be defined several times, with later definitions taking precedence.
+/// A definition that references its own name sees the value it overrides, so 'PATH=${PATH}:dir'
+/// extends the existing value instead of referencing itself.
+struct Resolver<'a> {
+    base: &'a HashMap<String, Value>,
+    providers: &'a Providers,
+    definitions: HashMap<&'a str, Vec<&'a Definition>>,
+    resolved: HashMap<(String, usize), Value>,
+    in_progress: Vec<(String, usize)>,
+}
+impl<'a> Resolver<'a> {
+    fn new(
+        base: &'a HashMap<String, Value>,
+        definitions: &'a [Definition],
+        providers: &'a Providers,
+    ) -> Self {
+        let mut by_key: HashMap<&str, Vec<&Definition>> = HashMap::new();
+        for definition in definitions {
+            by_key.entry(&definition.key).or_default().push(definition);
+        }
+        Resolver {
+            base,
+            providers,
+            definitions: by_key,
+            resolved: HashMap::new(),
+            in_progress: Vec::new(),
+        }
+    }
+
+    fn top_level(&self, key: &str) -> Option<usize> {
+        self.definitions.get(key).map(|defs| defs.len() - 1)
+    }
+
+    fn cycle_error(&self, key: &str, level: usize) -> anyhow::Error {
+        let start = self
+            .in_progress
+            .iter()
+            .position(|(k, l)| k == key && *l == level)
+            .unwrap_or(0);
+        let cycle = &self.in_progress[start..];
+        let mut chain: Vec<&str> = cycle.iter().map(|(k, _)| k.as_str()).collect();
+        chain.push(key);
+        let mut message = format!("Cyclic variable definition {}", chain.join(" -> "));
+        for (k, l) in cycle {
+            let definition = self.definitions[k.as_str()][*l];
+            message.push_str(&format!("\n  '{}' defined at {}", k, definition.origin));
+        }
+        anyhow::anyhow!(message)
+    }
+
+    /// Resolve the value of a key, as defined at a level in the key's definition stack.
+    /// A level of None refers to the base variables, which are never expanded.
+    fn resolve(&mut self, key: &str, level: Option<usize>) -> Result<Option<Value>> {
+        let Some(level) = level else {
+            return Ok(self.base.get(key).cloned());
+        };
+        if let Some(value) = self.resolved.get(&(key.to_string(), level)) {
+            return Ok(Some(value.clone()));
+        }
+        if self
+            .in_progress
+            .iter()
+            .any(|(k, l)| k == key && *l == level)
+        {
+            return Err(self.cycle_error(key, level));
+        }
+
+        let definition: &'a Definition = self.definitions[key][level];
+        trace!(
+            "Resolving variable '{}' defined at {}",
+            key,
+            definition.origin
+        );
+        self.in_progress.push((key.to_string(), level));
+        let providers = self.providers;
+        let mut lookup = |name: &str| {
+            if name == key {
+                self.resolve(name, level.checked_sub(1))
+            } else {
+                let top = self.top_level(name);
+                self.resolve(name, top)
+            }
+        };
+        let value = match &definition.value {
+            Value::Text(text) => expand(text, &mut lookup, providers),
+            // Elements that reference other lists are flattened into this list
+            Value::List(items) => items
+                .iter()
+                .map(|item| expand(item, &mut lookup, providers))
+                .collect::<Result<Vec<Value>>>()
+                .map(|values| {
+                    Value::List(values.iter().flat_map(|v| v.items().to_vec()).collect())
+                }),
+        };
+        self.in_progress.pop();
+
+        // Cycle errors already describe where each variable came from
+        let value = if self.in_progress.is_empty() {
+            value.with_context(|| {
+                format!(
+                    "Failed to expand variable '{}' defined at {}",
+                    key, definition.origin
+                )
+            })?
+        } else {
+            value?
+        };
+        self.resolved
+            .insert((key.to_string(), level), value.clone());
+        Ok(Some(value))
+    }
+}
+
+/// Fully expanded variables, ready to be substituted into paths
+#[derive(Debug, Clone)]
+pub struct Variables {
+    values: HashMap<String, Value>,
+    providers: Providers,
+}
+impl Variables {
+    /// Expand each definition on top of the base variables.
+    /// Base variables (like the environment) are taken literally and are never expanded.
+    pub fn resolve(
+        base: HashMap<String, String>,
+        definitions: &[Definition],
+        providers: Providers,
+    ) -> Result<Self> {
+        let base = base
+            .into_iter()
+            .map(|(key, value)| (key, Value::Text(value)))
+            .collect();
+        Variables::resolve_values(base, definitions, providers)
+    }
+
+    fn resolve_values(
+        base: HashMap<String, Value>,
+        definitions: &[Definition],
+        providers: Providers,
+    ) -> Result<Self> {
+        let mut resolver = Resolver::new(&base, definitions, &providers);
+        let mut resolved = HashMap::new();
+        for definition in definitions {
+            let top = resolver.top_level(&definition.key);
+            if let Some(value) = resolver.resolve(&definition.key, top)? {
+                resolved.insert(definition.key.clone(), value);
+            }
+        }
+
+        let mut values = base.clone();
+        values.extend(resolved);
+        Ok(Variables { values, providers })
+    }
+
+    /// New variables for a nested scope. Definitions shadow existing variables of the same name,
+    /// and may refer to the value they shadow
+    pub fn with_definitions(&self, definitions: &[Definition]) -> Result<Self> {
+        Variables::resolve_values(self.values.clone(), definitions, self.providers.clone())
+    }
+
+    fn expand_value(&self, text: &str) -> Result<Value> {
+        expand(
+            text,
+            &mut |key: &str| Ok(self.values.get(key).cloned()),
+            &self.providers,
+        )
+    }
+
+    /// Expand text that must result in a single value
+    pub fn expand(&self, text: &str) -> Result<String> {
+        single_value(self.expand_value(text)?, text)
+    }
+
+    /// Every variable as an environment for commands. List elements are joined with spaces
+    pub fn environment(&self) -> Vec<(String, String)> {
+        self.values
+            .iter()
+            .map(|(key, value)| (key.clone(), value.items().join(" ")))
+            .collect()
+    }
+
+    /// Expand text that may reference list variables, resulting in one value per list element
+    pub fn expand_all(&self, text: &str) -> Result<Vec<String>> {
+        Ok(self.expand_value(text)?.items().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definition(var: &str) -> Definition {
+        Definition::parse(var, Origin::CommandLine).unwrap()
+    }
+
+    fn list(key: &str, items: &[&str]) -> Definition {
+        let items = items.iter().map(|item| item.to_string()).collect();
+        Definition::new(key.to_string(), Value::List(items), Origin::CommandLine)
+    }
+
+    fn variables(base: &[(&str, &str)], definitions: &[Definition]) -> Result<Variables> {
+        let base = base
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Variables::resolve(base, definitions, Providers::new(Path::new("."), false))
+    }
+
+    fn expand_with(definitions: &[Definition], text: &str) -> Result<String> {
+        variables(&[("HOME", "/home/me")], definitions)?.expand(text)
+    }
+
+    fn error(result: Result<impl fmt::Debug>) -> String {
+        format!("{:#}", result.unwrap_err())
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(expand_with(&[], "${A:-a}").unwrap(), "a");
+        assert_eq!(
+            expand_with(&[definition("B=b")], "${A:-${B}}").unwrap(),
+            "b"
+        );
+        assert_eq!(expand_with(&[definition("A=")], "${A:-a}").unwrap(), "a");
+        assert_eq!(
+            expand_with(&[definition("A=set"), definition("B=b")], "${A:-${B}}").unwrap(),
+            "set"
+        );
+    }
+
+    #[test]
+    fn required_variables() {
+        assert_eq!(expand_with(&[definition("A=a")], "${A:?msg}").unwrap(), "a");
+        assert_eq!(
+            error(expand_with(&[], "${A:?set A first}")),
+            "A: set A first"
+        );
+        assert!(error(expand_with(&[definition("A=")], "${A:?}"))
+            .contains("Required variable 'A' is not set"));
+    }
+
+    #[test]
+    fn bare_variables() {
+        let defs = [definition("A=a"), definition("A_B=ab")];
+        assert_eq!(expand_with(&defs, "$A/x").unwrap(), "a/x");
+        assert_eq!(expand_with(&defs, "$A_B-$A").unwrap(), "ab-a");
+        assert!(error(expand_with(&defs, "$C")).contains("Undefined variable 'C'"));
+    }
+
+    #[test]
+    fn leading_tilde() {
+        assert_eq!(expand_with(&[], "~").unwrap(), "/home/me");
+        assert_eq!(expand_with(&[], "~/.bashrc").unwrap(), "/home/me/.bashrc");
+        assert_eq!(expand_with(&[], "a/~/b").unwrap(), "a/~/b");
+        assert_eq!(expand_with(&[], "~user").unwrap(), "~user");
+    }
+
+    #[test]
+    fn escapes() {
+        let defs = [definition("A=a")];
+        assert_eq!(expand_with(&defs, "$$A").unwrap(), "$A");
+        assert_eq!(expand_with(&defs, "$${A}").unwrap(), "${A}");
+        assert_eq!(expand_with(&defs, "$$$A").unwrap(), "$a");
+        assert!(error(expand_with(&defs, "cost$")).contains("Use '$$' for a literal '$'"));
+    }
+
+    #[test]
+    fn escaped_text_expands_to_itself() {
+        let defs = [definition("A=a")];
+        for text in ["$A", "${A}", "$$A", "cost$", "dir/$HOME"] {
+            assert_eq!(expand_with(&defs, &escape_variables(text)).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn invalid_names() {
+        assert!(error(expand_with(&[], "${1A}")).contains("Invalid variable name in '${1A}'"));
+        assert!(error(expand_with(&[], "${A-b}")).contains("Invalid variable name"));
+        assert!(error(expand_with(&[], "$1")).contains("Invalid variable reference"));
+        assert!(error(expand_with(&[], "${A")).contains("Unterminated '${'"));
+        assert!(Definition::parse("1A=x", Origin::CommandLine).is_err());
+        assert!(Definition::parse("A-B=x", Origin::CommandLine).is_err());
+    }
+
+    #[test]
+    fn nested_braces() {
+        let defs = [definition("B=b")];
+        assert_eq!(expand_with(&defs, "${A:-{${B}}}x").unwrap(), "{b}x");
+    }
+
+    #[test]
+    fn cycles_report_the_chain() {
+        let defs = [
+            definition("A=${B}"),
+            definition("B=${C}"),
+            definition("C=${A}"),
+        ];
+        let message = error(variables(&[], &defs));
+        assert!(
+            message.contains("Cyclic variable definition A -> B -> C -> A"),
+            "{}",
+            message
+        );
+        assert!(message.contains("'C' defined at the command line"));
+
+        let message = error(variables(&[], &[definition("A=x${A}")]));
+        assert!(message.contains("Undefined variable 'A'"), "{}", message);
+    }
+
+    #[test]
+    fn self_references_see_the_shadowed_value() {
+        let vars = variables(&[("PATH", "/bin")], &[definition("PATH=${PATH}:/opt")]).unwrap();
+        assert_eq!(vars.expand("$PATH").unwrap(), "/bin:/opt");
+
+        let defs = [
+            definition("A=1"),
+            definition("A=${A}2"),
+            definition("A=${A}3"),
+        ];
+        assert_eq!(expand_with(&defs, "$A").unwrap(), "123");
+
+        // Other variables see the final value
+        let defs = [
+            definition("B=${A}"),
+            definition("A=1"),
+            definition("A=${A}2"),
+        ];
+        assert_eq!(expand_with(&defs, "$B").unwrap(), "12");
+    }
+
+    #[test]
+    fn nested_scopes_shadow_outer_variables() {
+        let outer = variables(&[], &[definition("A=outer"), definition("B=${A}")]).unwrap();
+        let inner = outer
+            .with_definitions(&[definition("A=${A}/inner")])
+            .unwrap();
+        assert_eq!(inner.expand("$A").unwrap(), "outer/inner");
+        assert_eq!(inner.expand("$B").unwrap(), "outer");
+        assert_eq!(outer.expand("$A").unwrap(), "outer");
+    }
+
+    #[test]
+    fn lists_fan_out() {
+        let vars = variables(&[("HOME", "/home/me")], &[list("SHELLS", &["bash", "zsh"])]).unwrap();
+        assert_eq!(
+            vars.expand_all("~/.${SHELLS}rc").unwrap(),
+            ["/home/me/.bashrc", "/home/me/.zshrc"]
+        );
+        assert_eq!(vars.expand_all("plain").unwrap(), ["plain"]);
+        assert!(error(vars.expand("$SHELLS")).contains("expands to a list of 2 values"));
+    }
+
+    #[test]
+    fn lists_multiply() {
+        let defs = [list("A", &["a", "b"]), list("N", &["1", "2"])];
+        let vars = variables(&[], &defs).unwrap();
+        assert_eq!(vars.expand_all("$A$N").unwrap(), ["a1", "a2", "b1", "b2"]);
+    }
+
+    #[test]
+    fn list_elements_referencing_lists_are_flattened() {
+        let defs = [list("A", &["a", "b"]), list("B", &["${A}", "c"])];
+        let vars = variables(&[], &defs).unwrap();
+        assert_eq!(vars.expand_all("$B").unwrap(), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn commands_need_to_be_allowed() {
+        let message = error(expand_with(&[], "${cmd:echo hi}"));
+        assert!(
+            message.contains("running commands is not allowed"),
+            "{}",
+            message
+        );
+        assert!(message.contains("--allow-commands"));
+
+        let providers = Providers::new(Path::new("."), true);
+        let vars = Variables::resolve(HashMap::new(), &[], providers).unwrap();
+        assert_eq!(vars.expand("${cmd:echo hi}").unwrap(), "hi");
+    }
+}
@@ -0,0 +1,214 @@
+use crate::edit::Document;
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use std::fs;
+use std::path::Path;
+
+/// The version of the stowfile format this nstow writes. Stowfiles without a 'version' key are
+/// version 0, the layout from before stowfiles were versioned
+pub const STOWFILE_VERSION: u64 = 1;
+
+const VERSION_KEY: &str = "version";
+const MIN_NSTOW_VERSION_KEY: &str = "min_nstow_version";
+
+/// Parse a version like '1.2.3' into its numbers. Missing minor and patch numbers are 0, and
+/// anything after a '-' or '+' is ignored
+fn parse_nstow_version(text: &str) -> Result<[u64; 3]> {
+    let release = text
+        .trim()
+        .trim_start_matches('v')
+        .split(['-', '+'])
+        .next()
+        .unwrap_or_default();
+    let mut numbers = [0; 3];
+    let parts: Vec<&str> = release.split('.').collect();
+    if parts.len() > numbers.len() {
+        bail!("Invalid nstow version '{}'", text);
+    }
+    for (number, part) in numbers.iter_mut().zip(parts) {
+        *number = part
+            .parse()
+            .with_context(|| format!("Invalid nstow version '{}'", text))?;
+    }
+    Ok(numbers)
+}
+
+/// The stowfile's format version, refusing versions this nstow doesn't know
+fn stowfile_version(contents: &serde_yaml::Value, stowfile: &Path) -> Result<u64> {
+    let version = match contents.get(VERSION_KEY) {
+        None => return Ok(0),
+        Some(version) => version,
+    };
+    let Some(version) = version.as_u64() else {
+        bail!(
+            "Malformatted stowfile. Expected '{}' to be a whole number",
+            VERSION_KEY
+        );
+    };
+    if version > STOWFILE_VERSION {
+        bail!(
+            "Stowfile {:?} uses version {} of the stowfile format, but nstow {} only knows versions up to {}. Upgrade nstow to use it",
+            stowfile,
+            version,
+            env!("CARGO_PKG_VERSION"),
+            STOWFILE_VERSION
+        );
+    }
+    Ok(version)
+}
+
+/// The format version of a stowfile this nstow is able to read
+fn readable_version(contents: &serde_yaml::Value, stowfile: &Path) -> Result<u64> {
+    if let Some(required) = contents.get(MIN_NSTOW_VERSION_KEY) {
+        // Versions like '1.2' are read as numbers unless they are quoted
+        let required = match required {
+            serde_yaml::Value::String(required) => required.clone(),
+            serde_yaml::Value::Number(required) => required.to_string(),
+            _ => bail!(
+                "Malformatted stowfile. Expected '{}' to be a version like '1.2.3'",
+                MIN_NSTOW_VERSION_KEY
+            ),
+        };
+        let running = env!("CARGO_PKG_VERSION");
+        if parse_nstow_version(&required)? > parse_nstow_version(running)? {
+            bail!(
+                "Stowfile {:?} requires nstow {} or newer, but this is nstow {}",
+                stowfile,
+                required,
+                running
+            );
+        }
+    }
+
+    stowfile_version(contents, stowfile)
+}
+
+/// Check that this nstow can read a stowfile, before anything else in it is looked at
+pub fn check_stowfile(contents: &serde_yaml::Value, stowfile: &Path) -> Result<()> {
+    let version = readable_version(contents, stowfile)?;
+    if version < STOWFILE_VERSION {
+        debug!(
+            "Stowfile {:?} uses version {} of the stowfile format. Run 'nstow migrate' to upgrade it",
+            stowfile, version
+        );
+    }
+    Ok(())
+}
+
+/// Upgrade a stowfile to the current format version, keeping its comments and layout
+pub fn migrate_stowfile(stowfile: &Path, dry_run: bool) -> Result<()> {
+    let original = fs::read_to_string(stowfile)?;
+    let contents: serde_yaml::Value = serde_yaml::from_str(&original)?;
+    let version = readable_version(&contents, stowfile)?;
+    if version == STOWFILE_VERSION {
+        info!(
+            "{:?} already uses version {} of the stowfile format",
+            stowfile, version
+        );
+        return Ok(());
+    }
+
+    // Version 1 only adds the 'version' key, so there is nothing else to change yet
+    info!(
+        "Upgrading {:?} from version {} to {}",
+        stowfile, version, STOWFILE_VERSION
+    );
+    let mut document = Document::parse(&original)?;
+    document.set_top_level(VERSION_KEY, &STOWFILE_VERSION.into())?;
+    let migrated = document.into_text()?;
+
+    if dry_run {
+        print!("{}", migrated);
+        return Ok(());
+    }
+    info!("Writing {:?}", stowfile);
+    fs::write(stowfile, migrated)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch_dir;
+
+    fn yaml(text: &str) -> serde_yaml::Value {
+        serde_yaml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn nstow_versions_parse() {
+        assert_eq!(parse_nstow_version("1.2.3").unwrap(), [1, 2, 3]);
+        assert_eq!(parse_nstow_version("1.2").unwrap(), [1, 2, 0]);
+        assert_eq!(parse_nstow_version("v1.2.3-rc1").unwrap(), [1, 2, 3]);
+        assert_eq!(parse_nstow_version("1.2.3+build").unwrap(), [1, 2, 3]);
+        assert!(parse_nstow_version("1.2.3.4").is_err());
+        assert!(parse_nstow_version("one").is_err());
+    }
+
+    #[test]
+    fn min_nstow_version_may_be_a_number_or_a_string() {
+        let path = Path::new("stowfile");
+        let unquoted = yaml("version: 1\nmin_nstow_version: 0.1\n");
+        assert_eq!(readable_version(&unquoted, path).unwrap(), 1);
+        let prerelease = yaml("version: 1\nmin_nstow_version: v0.1.0-rc1\n");
+        assert_eq!(readable_version(&prerelease, path).unwrap(), 1);
+
+        // Read as the number 1.2, which is still compared as a version
+        let too_new = yaml("version: 1\nmin_nstow_version: 1.2\n");
+        let error = readable_version(&too_new, path).unwrap_err();
+        assert!(error.to_string().contains("requires nstow 1.2 or newer"));
+        let invalid = yaml("version: 1\nmin_nstow_version: 1.2.3.4\n");
+        assert!(readable_version(&invalid, path).is_err());
+    }
+
+    #[test]
+    fn stowfile_versions() {
+        let path = Path::new("stowfile");
+        assert_eq!(stowfile_version(&yaml("stow: []"), path).unwrap(), 0);
+        assert_eq!(stowfile_version(&yaml("version: 1"), path).unwrap(), 1);
+        let error = stowfile_version(&yaml("version: 2"), path).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("uses version 2 of the stowfile format"));
+        assert!(stowfile_version(&yaml("version: one"), path).is_err());
+    }
+
+    #[test]
+    fn migrating_adds_the_version_and_keeps_comments() {
+        let dir = scratch_dir("migrate");
+        let stowfile = dir.join("stowfile");
+        fs::write(
+            &stowfile,
+            "# My dotfiles\n\nstow:\n  # Shell\n  - src: bashrc\n    links:\n      - ${HOME}/.bashrc\n",
+        )
+        .unwrap();
+        migrate_stowfile(&stowfile, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(&stowfile).unwrap(),
+            "# My dotfiles\n\nversion: 1\n\nstow:\n  # Shell\n  - src: bashrc\n    links:\n      - ${HOME}/.bashrc\n"
+        );
+    }
+
+    #[test]
+    fn migrating_a_current_stowfile_changes_nothing() {
+        let dir = scratch_dir("migrate-current");
+        let stowfile = dir.join("stowfile");
+        // Unformatted, so any rewrite would show
+        let text = "version:   1\nstow:\n  - src: 'bashrc'\n    links: [\"${HOME}/.bashrc\"]\n";
+        fs::write(&stowfile, text).unwrap();
+        migrate_stowfile(&stowfile, false).unwrap();
+        assert_eq!(fs::read_to_string(&stowfile).unwrap(), text);
+    }
+
+    #[test]
+    fn migrating_a_newer_stowfile_is_refused() {
+        let dir = scratch_dir("migrate-newer");
+        let stowfile = dir.join("stowfile");
+        fs::write(&stowfile, "version: 2\nstow: []\n").unwrap();
+        assert!(migrate_stowfile(&stowfile, false).is_err());
+        assert_eq!(
+            fs::read_to_string(&stowfile).unwrap(),
+            "version: 2\nstow: []\n"
+        );
+    }
+}